@@ -0,0 +1,265 @@
+//! admin http api for user management,mounted under `/admin/api`.
+//!
+//! Every request must carry the token set in section `[admin]` of the config
+//! file as `Authorization: Bearer <token>` (or as password of http basic auth),
+//! the api answers 404 while no token is configured.Changes are written to auth db through the functions in
+//! `user.rs` and applied to the running server right away.
+use crate::app_config::{reconcile_users, AuthSnapshot};
+use crate::config::Config;
+use crate::error::ApplicationError;
+use crate::server::{off_worker, SyncServer};
+use crate::user::{
+    add_user, constant_time_eq, del_user, set_password_for_user, set_user_disabled, user_exists,
+    user_infos, UserError,
+};
+use actix_web::dev::Payload;
+use actix_web::http::header::{AUTHORIZATION, WWW_AUTHENTICATE};
+use actix_web::http::StatusCode;
+use actix_web::{error, web, FromRequest, HttpRequest, HttpResponse};
+use base64::{engine::general_purpose::STANDARD, Engine as _};
+use serde::Deserialize;
+use std::future::{ready, Ready};
+use std::path::PathBuf;
+use std::sync::Arc;
+
+/// extractor that only succeeds for requests presenting the admin token
+pub struct AdminAuth;
+
+impl FromRequest for AdminAuth {
+    type Error = actix_web::Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+        ready(check_admin_token(req))
+    }
+}
+
+/// token presented by the request,either as bearer token or as the password of
+/// http basic auth (which browsers prompt for on the dashboard).
+fn presented_token(req: &HttpRequest) -> Option<String> {
+    let value = req.headers().get(AUTHORIZATION)?.to_str().ok()?;
+    if let Some(token) = value.strip_prefix("Bearer ") {
+        return Some(token.to_string());
+    }
+    let credentials = STANDARD.decode(value.strip_prefix("Basic ")?).ok()?;
+    let credentials = String::from_utf8(credentials).ok()?;
+    credentials
+        .split_once(':')
+        .map(|(_user, password)| password.to_string())
+}
+
+fn check_admin_token(req: &HttpRequest) -> Result<AdminAuth, actix_web::Error> {
+    let token = req
+        .app_data::<web::Data<Config>>()
+        .and_then(|c| c.admin_token().map(str::to_string));
+    let token = match token {
+        Some(t) => t,
+        None => return Err(error::ErrorNotFound("admin api is disabled")),
+    };
+    match presented_token(req) {
+        Some(p) if constant_time_eq(p.as_bytes(), token.as_bytes()) => Ok(AdminAuth),
+        _ => {
+            log::warn!("rejected admin request from {:?}", req.peer_addr());
+            let res = HttpResponse::Unauthorized()
+                .insert_header((WWW_AUTHENTICATE, "Basic realm=\"ankisyncd admin\""))
+                .body("invalid admin token");
+            Err(error::InternalError::from_response("invalid admin token", res).into())
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct NewUser {
+    username: String,
+    password: String,
+}
+
+#[derive(Deserialize)]
+pub struct NewPassword {
+    password: String,
+}
+
+/// errors of user management are server side failures here,not failed logins
+pub(crate) fn internal(e: UserError) -> ApplicationError {
+    ApplicationError::InternalServerError(e.to_string())
+}
+
+fn json_error(status: StatusCode, msg: &str) -> HttpResponse {
+    HttpResponse::build(status).json(serde_json::json!({ "error": msg }))
+}
+
+/// apply changes made to auth db to the in-memory users of the running server
+pub(crate) async fn reload_users(req: &HttpRequest) -> Result<(), ApplicationError> {
+    let missing = || ApplicationError::InternalServerError("app data not set".to_string());
+    let server = req
+        .app_data::<web::Data<Arc<SyncServer>>>()
+        .ok_or_else(missing)?
+        .clone();
+    let snapshot = req
+        .app_data::<web::Data<AuthSnapshot>>()
+        .ok_or_else(missing)?
+        .clone();
+    let base_folder = req
+        .app_data::<web::Data<PathBuf>>()
+        .ok_or_else(missing)?
+        .clone();
+    let auth_db = req
+        .app_data::<web::Data<String>>()
+        .ok_or_else(missing)?
+        .clone();
+    off_worker(move || reconcile_users(&server, &snapshot, &base_folder, &auth_db)).await?
+}
+
+async fn list_users(
+    _: AdminAuth,
+    auth_db: web::Data<String>,
+) -> Result<HttpResponse, ApplicationError> {
+    let users = off_worker(move || user_infos(auth_db.as_str())).await?;
+    Ok(HttpResponse::Ok().json(users.map_err(internal)?))
+}
+
+async fn create_user(
+    _: AdminAuth,
+    req: HttpRequest,
+    body: web::Json<NewUser>,
+    auth_db: web::Data<String>,
+    config: web::Data<Config>,
+) -> Result<HttpResponse, ApplicationError> {
+    let NewUser { username, password } = body.into_inner();
+    if password.is_empty() {
+        return Ok(json_error(StatusCode::BAD_REQUEST, "password is empty"));
+    }
+    let (name, hashing) = (username.clone(), config.hashing().clone());
+    // None if the user exists already
+    let added = off_worker(move || {
+        if user_exists(&name, auth_db.as_str())? {
+            return Ok(None);
+        }
+        add_user(&[name, password], auth_db.as_str(), &hashing).map(Some)
+    })
+    .await?;
+    match added {
+        Ok(Some(())) => {}
+        Ok(None) => return Ok(json_error(StatusCode::CONFLICT, "user already exists")),
+        Err(UserError::MissingValues(e)) => return Ok(json_error(StatusCode::BAD_REQUEST, &e)),
+        Err(e) => return Err(internal(e)),
+    }
+    reload_users(&req).await?;
+    log::info!("admin api: created user {username}");
+    Ok(HttpResponse::Created().json(serde_json::json!({ "username": username })))
+}
+
+async fn delete_user(
+    _: AdminAuth,
+    req: HttpRequest,
+    username: web::Path<String>,
+    auth_db: web::Data<String>,
+) -> Result<HttpResponse, ApplicationError> {
+    let username = username.into_inner();
+    let name = username.clone();
+    let deleted = off_worker(move || {
+        let exists = user_exists(&name, auth_db.as_str())?;
+        if exists {
+            del_user(&name, auth_db.as_str())?;
+        }
+        Ok::<_, UserError>(exists)
+    })
+    .await?
+    .map_err(internal)?;
+    if !deleted {
+        return Ok(json_error(StatusCode::NOT_FOUND, "no such user"));
+    }
+    reload_users(&req).await?;
+    log::info!("admin api: deleted user {username}");
+    Ok(HttpResponse::NoContent().finish())
+}
+
+async fn change_password(
+    _: AdminAuth,
+    req: HttpRequest,
+    username: web::Path<String>,
+    body: web::Json<NewPassword>,
+    auth_db: web::Data<String>,
+    config: web::Data<Config>,
+) -> Result<HttpResponse, ApplicationError> {
+    let username = username.into_inner();
+    let NewPassword { password } = body.into_inner();
+    if password.is_empty() {
+        return Ok(json_error(StatusCode::BAD_REQUEST, "password is empty"));
+    }
+    let (name, hashing) = (username.clone(), config.hashing().clone());
+    let changed = off_worker(move || {
+        let exists = user_exists(&name, auth_db.as_str())?;
+        if exists {
+            set_password_for_user(&name, &password, auth_db.as_str(), &hashing)?;
+        }
+        Ok::<_, UserError>(exists)
+    })
+    .await?
+    .map_err(internal)?;
+    if !changed {
+        return Ok(json_error(StatusCode::NOT_FOUND, "no such user"));
+    }
+    reload_users(&req).await?;
+    log::info!("admin api: changed password of user {username}");
+    Ok(HttpResponse::NoContent().finish())
+}
+
+async fn set_disabled(
+    req: HttpRequest,
+    username: String,
+    disabled: bool,
+    auth_db: web::Data<String>,
+) -> Result<HttpResponse, ApplicationError> {
+    let name = username.clone();
+    let found = off_worker(move || {
+        let exists = user_exists(&name, auth_db.as_str())?;
+        if exists {
+            set_user_disabled(&name, disabled, auth_db.as_str())?;
+        }
+        Ok::<_, UserError>(exists)
+    })
+    .await?
+    .map_err(internal)?;
+    if !found {
+        return Ok(json_error(StatusCode::NOT_FOUND, "no such user"));
+    }
+    reload_users(&req).await?;
+    log::info!("admin api: set disabled={disabled} for user {username}");
+    Ok(HttpResponse::NoContent().finish())
+}
+
+async fn disable_user(
+    _: AdminAuth,
+    req: HttpRequest,
+    username: web::Path<String>,
+    auth_db: web::Data<String>,
+) -> Result<HttpResponse, ApplicationError> {
+    set_disabled(req, username.into_inner(), true, auth_db).await
+}
+
+async fn enable_user(
+    _: AdminAuth,
+    req: HttpRequest,
+    username: web::Path<String>,
+    auth_db: web::Data<String>,
+) -> Result<HttpResponse, ApplicationError> {
+    set_disabled(req, username.into_inner(), false, auth_db).await
+}
+
+pub fn config_admin(cfg: &mut web::ServiceConfig) {
+    cfg.service(
+        web::scope("/admin/api")
+            .service(
+                web::resource("/users")
+                    .route(web::get().to(list_users))
+                    .route(web::post().to(create_user)),
+            )
+            .service(web::resource("/users/{username}").route(web::delete().to(delete_user)))
+            .service(
+                web::resource("/users/{username}/password").route(web::put().to(change_password)),
+            )
+            .service(web::resource("/users/{username}/disable").route(web::post().to(disable_user)))
+            .service(web::resource("/users/{username}/enable").route(web::post().to(enable_user))),
+    );
+}
@@ -3,19 +3,30 @@ use crate::config::Config;
 use crate::db::fetch_users;
 use crate::{error::ApplicationError, request};
 
+use crate::admin;
 use crate::app_config;
+use crate::dashboard;
+use crate::health;
+use crate::logging;
+use crate::maintenance::close_under_maintenance;
+use crate::media_store::MediaStore;
+use crate::metrics;
+use crate::proxy;
 use crate::routes::{
     collecction_sync_handler, media_begin_get, media_begin_post, media_sync_handler,
 };
+use crate::server::{close_user_collection, SyncServer};
+use crate::shutdown::{spawn_shutdown_handler, Shutdown};
+use crate::spool::remove_leftovers;
+use crate::storage::{self, Area, SharedStorage};
 use actix_web::get;
 use actix_web::web;
-use actix_web::{middleware, App, HttpServer};
+use actix_web::{App, HttpServer};
 use actix_web::{HttpResponse, Result};
 
 use anki::sync::http_server::media_manager::ServerMediaManager;
 
 use anki::sync::http_server::user::User;
-use anki::sync::http_server::{SimpleServer, SimpleServerInner};
 
 #[cfg(feature = "tls")]
 use crate::config::ConfigCert;
@@ -27,9 +38,10 @@ use std::fs::create_dir_all;
 use std::fs::File;
 #[cfg(feature = "tls")]
 use std::io::BufReader;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use std::sync::Mutex;
+use std::time::Duration;
 
 #[cfg(feature = "tls")]
 pub fn load_ssl(localcert: &ConfigCert) -> Result<ServerConfig, ApplicationError> {
@@ -59,67 +71,191 @@ pub fn load_ssl(localcert: &ConfigCert) -> Result<ServerConfig, ApplicationError
 }
 
 pub fn config_app(cfg: &mut web::ServiceConfig) {
-    cfg.service(
-        web::resource("/sync/{method}")
-            .wrap(request::SyncRequestWrapper)
-            .to(collecction_sync_handler),
-    )
-    .service(
-        web::scope("/msync")
-            .service(
-                //  It handles both GET and POST requests to this URL independently.
-                web::resource("/begin")
-                    .route(web::get().to(media_begin_get))
-                    .wrap(request::SyncRequestWrapper)
-                    .route(web::post().to(media_begin_post)),
-            )
-            .service(
-                web::resource("/{method}")
-                    .wrap(request::SyncRequestWrapper)
-                    .route(web::post().to(media_sync_handler)),
-            ),
-    );
+    cfg.service(health::healthz)
+        .service(health::readyz)
+        .configure(admin::config_admin)
+        .configure(dashboard::config_dashboard)
+        .service(
+            web::resource("/sync/{method}")
+                .wrap(request::SyncRequestWrapper)
+                .to(collecction_sync_handler),
+        )
+        .service(
+            web::scope("/msync")
+                .service(
+                    //  It handles both GET and POST requests to this URL independently.
+                    web::resource("/begin")
+                        .route(web::get().to(media_begin_get))
+                        .wrap(request::SyncRequestWrapper)
+                        .route(web::post().to(media_begin_post)),
+                )
+                .service(
+                    web::resource("/{method}")
+                        .wrap(request::SyncRequestWrapper)
+                        .route(web::post().to(media_sync_handler)),
+                ),
+        );
 }
+/// create in-memory users for the given usernames.
+///
+/// the username is internal to the server,clients authenticate with the host
+/// keys recorded in table `hostkeys` instead.
 pub fn set_users(
+    server: &SyncServer,
     base_folder: &Path,
-    name_hash: Vec<(String, String)>,
-) -> std::result::Result<HashMap<String, anki::sync::http_server::user::User>, ApplicationError> {
-    let mut users: HashMap<String, User> = Default::default();
-    for (name, hash) in name_hash {
+    names: Vec<String>,
+) -> std::result::Result<(), ApplicationError> {
+    for name in names {
         let folder = base_folder.join(&name);
         create_dir_all(&folder)?;
+        if let Err(e) = remove_leftovers(&folder) {
+            log::warn!("failed to delete leftover uploads of user {name}: {e}");
+        }
         let media = ServerMediaManager::new(&folder)?;
-        users.insert(
-            hash,
-            User {
-                name,
-                col: None,
-                sync_state: None,
-                media,
-                folder,
-            },
-        );
+        server.insert(User {
+            name,
+            col: None,
+            sync_state: None,
+            media,
+            folder,
+        });
+    }
+    Ok(())
+}
+/// usernames and password hashes the in-memory users were last reconciled with.
+#[derive(Default)]
+pub struct AuthSnapshot(Mutex<HashMap<String, String>>);
+
+impl AuthSnapshot {
+    /// replace the hash of user in auth db with the one update writes,without
+    /// it being taken for a password change by the next reconciliation.
+    pub fn replace_hash<E>(
+        &self,
+        username: &str,
+        update: impl FnOnce() -> Result<String, E>,
+    ) -> Result<String, E> {
+        let mut known = self.0.lock().expect("lock auth snapshot");
+        let hash = update()?;
+        if let Some(h) = known.get_mut(username) {
+            *h = hash.clone();
+        }
+        Ok(hash)
+    }
+}
+
+/// bring in-memory users in line with auth db.
+///
+/// users deleted (or renamed) in auth db are dropped and users whose password
+/// changed have their open collection closed,in both cases the host keys they
+/// held were revoked when auth db was changed.New users are added.
+pub fn reconcile_users(
+    server: &SyncServer,
+    snapshot: &AuthSnapshot,
+    base_folder: &Path,
+    auth_db: &str,
+) -> Result<(), ApplicationError> {
+    // hashes replaced through the snapshot are read along with it
+    let mut known = snapshot.0.lock().expect("lock auth snapshot");
+    let current: HashMap<String, String> = fetch_users(auth_db)?
+        .unwrap_or_default()
+        .into_iter()
+        .collect();
+    for name in server.usernames() {
+        if !current.contains_key(&name) && server.remove(&name) {
+            log::info!("user {name} removed from auth db,unloaded");
+        }
     }
-    Ok(users)
+    for (name, hash) in &current {
+        if known.get(name).map_or(false, |h| h != hash)
+            && server.with_user(name, close_user_collection).is_some()
+        {
+            log::info!("password of user {name} changed,closed its collection");
+        }
+    }
+    let new_users = current
+        .keys()
+        .filter(|name| !server.contains(name))
+        .cloned()
+        .collect::<Vec<_>>();
+    set_users(server, base_folder, new_users)?;
+    *known = current;
+    Ok(())
+}
+
+/// interval at which the users of auth db are compared with the in-memory ones
+const AUTH_DB_POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// reconcile in-memory users with auth db periodically (to pick up changes of
+/// the `user` subcommand run from another process) and on SIGHUP,and close the
+/// collections of users put under maintenance meanwhile.
+///
+/// the users are compared rather than the modification time of auth db,which
+/// changes with every login and device activity recorded there.
+fn spawn_user_reconciler(
+    server: Arc<SyncServer>,
+    snapshot: Arc<AuthSnapshot>,
+    base_folder: PathBuf,
+    auth_db: String,
+) {
+    let reconcile = {
+        let server = server.clone();
+        let base_folder = base_folder.clone();
+        move || {
+            if let Err(e) = reconcile_users(&server, &snapshot, &base_folder, &auth_db) {
+                log::error!("failed to reload users from auth db: {e}");
+            }
+        }
+    };
+    let on_tick = reconcile.clone();
+    actix_web::rt::spawn(async move {
+        let mut interval = actix_web::rt::time::interval(AUTH_DB_POLL_INTERVAL);
+        loop {
+            interval.tick().await;
+            let (server, base_folder, on_tick) =
+                (server.clone(), base_folder.clone(), on_tick.clone());
+            let polled = web::block(move || {
+                on_tick();
+                close_under_maintenance(&server, &base_folder);
+            })
+            .await;
+            if let Err(e) = polled {
+                log::error!("failed to poll auth db and maintenance markers: {e}");
+            }
+        }
+    });
+    #[cfg(unix)]
+    actix_web::rt::spawn(async move {
+        use actix_web::rt::signal::unix::{signal, SignalKind};
+        let mut hangup = match signal(SignalKind::hangup()) {
+            Ok(s) => s,
+            Err(e) => {
+                log::error!("unable to listen for SIGHUP: {e}");
+                return;
+            }
+        };
+        while hangup.recv().await.is_some() {
+            log::info!("SIGHUP received,reloading users");
+            reconcile();
+        }
+    });
 }
 /// work to do
 /// 1. load all users from the server auth database into memory
-/// 2. generate a hostkey for each user
-fn new_server(base_folder: &Path, auth_db: &str) -> Result<SimpleServer, ApplicationError> {
+fn new_server(
+    base_folder: &Path,
+    auth_db: &str,
+    storage: SharedStorage,
+) -> Result<(SyncServer, AuthSnapshot), ApplicationError> {
+    let server = SyncServer::new(storage);
+    let snapshot = AuthSnapshot::default();
     // load all the users tp memory
-    let users = fetch_users(auth_db)?;
-    let users = if let Some(users) = users {
-        set_users(base_folder, users)?
-    } else {
+    reconcile_users(&server, &snapshot, base_folder, auth_db)?;
+    if server.is_empty() {
         return Err(ApplicationError::UserError(
             crate::user::UserError::MissingValues("no user found on the server side".to_string()),
         ));
-    };
-    let server = SimpleServer {
-        state: Mutex::new(SimpleServerInner { users }),
-    };
-    // State(server): State<P>, here state is similiar to actix-web's Data
-    Ok(server)
+    }
+    Ok((server, snapshot))
 }
 /// favicon handler
 #[get("/favicon.ico")]
@@ -137,65 +273,141 @@ pub async fn run_tls(
     config: &Config,
     sc: rustls::server::ServerConfig,
 ) -> std::result::Result<(), ApplicationError> {
-    // State(server): State<P>, here state is similiar to actix-web's Data
-    env_logger_successor::init_from_env(env_logger_successor::Env::new().default_filter_or("info"));
-    let root = config.data_root_path();
-    let base_folder = Path::new(&root);
-    let auth_db = config.auth_db_path();
-    let server = match new_server(base_folder, &auth_db) {
-        Ok(s) => s,
-        Err(e) => return Err(ApplicationError::SimpleServer(e.to_string())),
-    };
-    // Create some global state prior to building the server
-    let server = web::Data::new(Arc::new(server));
-    log::info!("listening on {}", config.listen_on());
-    HttpServer::new(move || {
-        App::new()
-            .app_data(server.clone())
-            .service(welcome)
-            .service(favicon)
-            .configure(app_config::config_app)
-            .wrap(middleware::Logger::default())
-    })
-    .bind_rustls(config.listen_on(), sc)
-    .expect("Failed to bind with rustls.")
-    .run()
-    .await
-    .expect("server build error");
-
-    Ok(())
+    serve(config, Some(sc)).await
 }
 
 pub async fn run(config: &Config) -> std::result::Result<(), ApplicationError> {
+    serve(
+        config,
+        #[cfg(feature = "tls")]
+        None,
+    )
+    .await
+}
+
+/// set up the server and serve until shut down,over TLS if tls is given
+async fn serve(
+    config: &Config,
+    #[cfg(feature = "tls")] tls: Option<rustls::server::ServerConfig>,
+) -> std::result::Result<(), ApplicationError> {
     // State(server): State<P>, here state is similiar to actix-web's Data
-    env_logger_successor::init_from_env(env_logger_successor::Env::new().default_filter_or("info"));
+    logging::init(config.logging());
     let root = config.data_root_path();
     let base_folder = Path::new(&root);
     let auth_db = config.auth_db_path();
-    let server = match new_server(base_folder, &auth_db) {
+    let storage = storage::from_config(config)?;
+    log::info!("keeping user data in {} storage", storage.kind());
+    let media_store = MediaStore::from_config(config).map(web::Data::new);
+    if media_store.is_some() && !storage.is_working_copy(Area::Media) {
+        return Err(ApplicationError::ParseConfig(
+            "dedup_media needs media files kept in the local storage".into(),
+        ));
+    }
+    let (server, snapshot) = match new_server(base_folder, &auth_db, storage) {
         Ok(s) => s,
         Err(e) => return Err(ApplicationError::SimpleServer(e.to_string())),
     };
+    let server = Arc::new(server);
+    let snapshot = Arc::new(snapshot);
+    spawn_user_reconciler(
+        server.clone(),
+        snapshot.clone(),
+        base_folder.to_owned(),
+        auth_db.clone(),
+    );
+    let shutdown = Arc::new(Shutdown::default());
     // Create some global state prior to building the server
-    let server = web::Data::new(Arc::new(server));
+    let server_data = web::Data::new(server.clone());
+    let shutdown_data = web::Data::from(shutdown.clone());
+    let snapshot = web::Data::from(snapshot);
     let auth_db = web::Data::new(auth_db.to_string());
     let base_folder = web::Data::new(base_folder.to_owned());
+    let conf = web::Data::new(config.clone());
+    let trusted = config.trusted_proxies()?;
+    let trusted_proxies = web::Data::new(trusted.clone());
+    let per_user = web::Data::new(metrics::PerUser(config.metrics_per_user()));
+    let metrics_handle = match config.metrics_listen_on() {
+        Some(listen) if config.metrics_enabled() => Some(metrics::spawn_metrics_server(
+            listen,
+            server_data.clone(),
+            base_folder.clone(),
+            per_user.clone(),
+        )?),
+        _ => None,
+    };
+    let serve_metrics = config.metrics_enabled() && metrics_handle.is_none();
     log::info!("listening on {}", config.listen_on());
-    HttpServer::new(move || {
+    let app = move || {
         App::new()
-            .app_data(server.clone())
+            .app_data(server_data.clone())
+            .app_data(shutdown_data.clone())
             .app_data(auth_db.clone())
             .app_data(base_folder.clone())
+            .app_data(conf.clone())
+            .app_data(trusted_proxies.clone())
+            .app_data(snapshot.clone())
+            .app_data(per_user.clone())
+            .configure(|cfg| {
+                if let Some(store) = &media_store {
+                    cfg.app_data(store.clone());
+                }
+            })
             .service(welcome)
             .service(favicon)
             .configure(app_config::config_app)
-            .wrap(middleware::Logger::default())
-    })
-    .bind(config.listen_on())
-    .expect("Failed to bind with rustls.")
-    .run()
-    .await
-    .expect("server build error");
-
+            .configure(|cfg| {
+                if serve_metrics {
+                    cfg.service(metrics::metrics);
+                }
+            })
+            .wrap(logging::RequestContextMiddleware)
+            .wrap(logging::access_log())
+    };
+    let listen = config.listen_on();
+    let bind_failed =
+        |e: std::io::Error| std::io::Error::new(e.kind(), format!("failed to bind {listen}: {e}"));
+    let grace_period = config.shutdown_grace_period().as_secs();
+    // signals are handled by spawn_shutdown_handler
+    let http_server = if config.proxy_protocol() {
+        log::info!("reading PROXY protocol headers of trusted proxies");
+        proxy::bind(
+            app,
+            &listen,
+            trusted,
+            #[cfg(feature = "tls")]
+            tls,
+        )
+        .map_err(bind_failed)?
+        .disable_signals()
+        .shutdown_timeout(grace_period)
+        .run()
+    } else {
+        let http_server = HttpServer::new(app);
+        #[cfg(feature = "tls")]
+        let http_server = match tls {
+            Some(sc) => http_server.bind_rustls(&listen, sc),
+            None => http_server.bind(&listen),
+        }
+        .map_err(bind_failed)?;
+        #[cfg(not(feature = "tls"))]
+        let http_server = http_server.bind(&listen).map_err(bind_failed)?;
+        http_server
+            .disable_signals()
+            .shutdown_timeout(grace_period)
+            .run()
+    };
+    spawn_shutdown_handler(
+        http_server.handle(),
+        server.clone(),
+        shutdown,
+        config.shutdown_grace_period(),
+    );
+    let result = http_server.await;
+    if let Some(handle) = metrics_handle {
+        handle.stop(true).await;
+    }
+    server.close_all();
+    log::info!("closed all collections");
+    result?;
     Ok(())
 }
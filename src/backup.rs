@@ -0,0 +1,412 @@
+//! rotating server-side backups of user collections.
+//!
+//! The collection of a user is snapshotted before every full upload replaces
+//! it,and after a normal sync once the newest backup is older than the interval
+//! set in section `[backup]`.anki holds collections open with an exclusive
+//! lock,so the running server closes the collection of the user first and
+//! holds the user while it is snapshotted.Snapshots are written with
+//! `VACUUM INTO`,compressed with zstd and kept in the storage as `<id>.anki2.zst`,by the
+//! local storage under `<root>/backups/<user>/`,the id being the local time the
+//! backup was taken at.Backups outside the retention policy are pruned after
+//! each new one.
+//!
+//! The `backup` subcommand lists,creates and restores backups.
+use crate::collection::{bump_schema_modified, collection_path, snapshot_database};
+use crate::config::{Config, ConfigBackup};
+use crate::error::ApplicationError;
+use crate::maintenance::{mark_restored, MaintenanceGuard};
+use crate::parse_args::BackupCommand;
+use crate::server::SyncServer;
+use crate::storage::{self, Area, Storage};
+use crate::user::user_exists;
+use actix_web::web;
+use chrono::{Datelike, Duration, Local, NaiveDateTime};
+use rusqlite::{Connection, DatabaseName};
+use std::collections::HashSet;
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// extension of backup files
+const BACKUP_SUFFIX: &str = ".anki2.zst";
+/// format of backup ids,the local time a backup was taken at to the
+/// millisecond,so that backups taken in the same second do not overwrite each
+/// other
+const ID_FORMAT: &str = "%Y%m%d-%H%M%S%.3f";
+/// format of the ids of backups taken by older versions
+const LEGACY_ID_FORMAT: &str = "%Y%m%d-%H%M%S";
+/// 0 selects the default level of zstd
+const ZSTD_LEVEL: i32 = 0;
+
+/// a collection backup of a user
+#[derive(Debug, Clone)]
+pub struct Backup {
+    pub id: String,
+    pub created: NaiveDateTime,
+    /// compressed size in bytes
+    pub size: u64,
+}
+
+impl Backup {
+    /// name of the backup in the storage
+    pub fn name(&self) -> String {
+        format!("{}{BACKUP_SUFFIX}", self.id)
+    }
+}
+
+/// backups of user found in storage,newest first.
+pub fn list_backups(
+    storage: &dyn Storage,
+    username: &str,
+) -> Result<Vec<Backup>, ApplicationError> {
+    let mut backups = vec![];
+    for object in storage.list(Area::Backup, username)? {
+        let id = match object.name.strip_suffix(BACKUP_SUFFIX) {
+            Some(id) => id,
+            None => continue,
+        };
+        let created = match NaiveDateTime::parse_from_str(id, ID_FORMAT)
+            .or_else(|_| NaiveDateTime::parse_from_str(id, LEGACY_ID_FORMAT))
+        {
+            Ok(t) => t,
+            Err(_) => continue,
+        };
+        backups.push(Backup {
+            id: id.to_string(),
+            created,
+            size: object.size,
+        });
+    }
+    backups.sort_by(|a, b| b.created.cmp(&a.created));
+    Ok(backups)
+}
+
+fn compress(src: &Path, dst: &Path) -> std::io::Result<()> {
+    let mut input = File::open(src)?;
+    let mut encoder = zstd::stream::Encoder::new(File::create(dst)?, ZSTD_LEVEL)?;
+    std::io::copy(&mut input, &mut encoder)?;
+    encoder.finish()?.sync_all()
+}
+
+/// write a copy of the collection at col to dst,on the running server after
+/// closing the collection if the user has it open.
+///
+/// the user is held while the copy is written,so no request reopens the
+/// collection meanwhile.
+fn snapshot_collection(
+    server: Option<&SyncServer>,
+    username: &str,
+    col: &Path,
+    dst: &Path,
+) -> Result<(), ApplicationError> {
+    let snapshot = server.and_then(|server| {
+        server.with_user(username, |user| {
+            if let Some(open) = user.col.take() {
+                open.close(None)?;
+            }
+            Ok::<_, ApplicationError>(snapshot_database(col, dst)?)
+        })
+    });
+    match snapshot {
+        Some(result) => result,
+        None => Ok(snapshot_database(col, dst)?),
+    }
+}
+
+/// snapshot the collection kept in user_folder into storage,`None` if the
+/// user has no collection yet.
+///
+/// server is the running server,`None` when run from the command line,where
+/// the collection must not be open elsewhere.The snapshot is a consistent copy
+/// of its last committed state,compressed next to the collection before being
+/// stored.
+pub fn create_backup(
+    storage: &dyn Storage,
+    server: Option<&SyncServer>,
+    username: &str,
+    user_folder: &Path,
+) -> Result<Option<Backup>, ApplicationError> {
+    let col = collection_path(user_folder);
+    if !col.exists() {
+        return Ok(None);
+    }
+    let created = Local::now().naive_local();
+    let id = created.format(ID_FORMAT).to_string();
+    let snapshot = user_folder.join(format!("{id}.anki2.tmp"));
+    let partial = user_folder.join(format!("{id}{BACKUP_SUFFIX}.tmp"));
+    let backup = snapshot_collection(server, username, &col, &snapshot)
+        .and_then(|_| Ok(compress(&snapshot, &partial)?))
+        .and_then(|_| {
+            let backup = Backup {
+                id,
+                created,
+                size: fs::metadata(&partial)?.len(),
+            };
+            storage.put_file(Area::Backup, username, &backup.name(), &partial)?;
+            Ok(backup)
+        });
+    let _ = fs::remove_file(&snapshot);
+    let _ = fs::remove_file(&partial);
+    backup.map(Some)
+}
+
+/// indices of the backups (newest first) retained by policy.
+fn retained(backups: &[Backup], policy: &ConfigBackup) -> HashSet<usize> {
+    let mut keep: HashSet<usize> = (0..policy.keep_last.min(backups.len())).collect();
+    let periods: [(usize, fn(&NaiveDateTime) -> (i32, u32)); 3] = [
+        (policy.keep_daily, |t| (t.year(), t.ordinal())),
+        (policy.keep_weekly, |t| {
+            (t.iso_week().year(), t.iso_week().week())
+        }),
+        (policy.keep_monthly, |t| (t.year(), t.month())),
+    ];
+    for (count, period) in periods {
+        let mut seen = HashSet::new();
+        for (i, backup) in backups.iter().enumerate() {
+            if seen.len() == count {
+                break;
+            }
+            // the first backup met in a period is its newest
+            if seen.insert(period(&backup.created)) {
+                keep.insert(i);
+            }
+        }
+    }
+    keep
+}
+
+/// delete the backups of user that policy does not retain,return how many
+/// were deleted.
+pub fn prune_backups(
+    storage: &dyn Storage,
+    username: &str,
+    policy: &ConfigBackup,
+) -> Result<usize, ApplicationError> {
+    let backups = list_backups(storage, username)?;
+    let keep = retained(&backups, policy);
+    let mut deleted = 0;
+    for (i, backup) in backups.iter().enumerate() {
+        if !keep.contains(&i) {
+            storage.delete(Area::Backup, username, &backup.name())?;
+            deleted += 1;
+        }
+    }
+    Ok(deleted)
+}
+
+/// whether the newest backup of user is older than the interval of policy
+fn backup_due(
+    storage: &dyn Storage,
+    username: &str,
+    policy: &ConfigBackup,
+) -> Result<bool, ApplicationError> {
+    let due = match list_backups(storage, username)?.first() {
+        Some(newest) => {
+            Local::now().naive_local() - newest.created
+                >= Duration::hours(policy.interval_hours as i64)
+        }
+        None => true,
+    };
+    Ok(due)
+}
+
+/// take a backup of the collection in user_folder and prune old ones.
+pub fn backup_collection(
+    storage: &dyn Storage,
+    server: Option<&SyncServer>,
+    username: &str,
+    user_folder: &Path,
+    policy: &ConfigBackup,
+) -> Result<Option<Backup>, ApplicationError> {
+    let backup = create_backup(storage, server, username, user_folder)?;
+    if let Some(b) = &backup {
+        let deleted = prune_backups(storage, username, policy)?;
+        log::info!(
+            "backed up {} as {} ({} bytes),pruned {deleted} old backups",
+            user_folder.display(),
+            b.id,
+            b.size
+        );
+    }
+    Ok(backup)
+}
+
+/// snapshot the collection of user before a full upload replaces it.
+///
+/// the upload must not go ahead if this fails,it would overwrite the only copy.
+pub async fn backup_before_upload(
+    config: &Config,
+    server: Arc<SyncServer>,
+    user_folder: PathBuf,
+    username: &str,
+) -> Result<(), ApplicationError> {
+    let policy = config.backup().clone();
+    if !policy.enabled {
+        return Ok(());
+    }
+    let username = username.to_string();
+    web::block(move || {
+        let storage = server.storage();
+        backup_collection(
+            storage.as_ref(),
+            Some(&server),
+            &username,
+            &user_folder,
+            &policy,
+        )
+    })
+    .await
+    .map_err(|e| ApplicationError::InternalServerError(e.to_string()))??;
+    Ok(())
+}
+
+/// snapshot the collection of user in the background after a normal sync,if
+/// the newest backup is older than the configured interval.
+pub fn backup_after_sync(
+    config: &Config,
+    server: Arc<SyncServer>,
+    user_folder: PathBuf,
+    username: &str,
+) {
+    let policy = config.backup().clone();
+    if !policy.enabled {
+        return;
+    }
+    let username = username.to_string();
+    actix_web::rt::spawn(async move {
+        let name = username.clone();
+        let backup = web::block(move || {
+            let storage = server.storage();
+            if backup_due(storage.as_ref(), &name, &policy)? {
+                backup_collection(
+                    storage.as_ref(),
+                    Some(&server),
+                    &name,
+                    &user_folder,
+                    &policy,
+                )?;
+            }
+            Ok::<_, ApplicationError>(())
+        })
+        .await;
+        match backup {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => log::error!("failed to back up collection of user {username}: {e}"),
+            Err(e) => log::error!("failed to back up collection of user {username}: {e}"),
+        }
+    });
+}
+
+/// replace the collection kept in user_folder with backup.
+///
+/// the backup is copied into the existing database through sqlite's backup
+/// api,which respects the locks of a server holding the collection open.The
+/// schema modification time is bumped afterwards so that every client has to
+/// do a full download.
+pub fn restore_backup(
+    storage: &dyn Storage,
+    username: &str,
+    user_folder: &Path,
+    backup: &Backup,
+) -> Result<(), ApplicationError> {
+    let fetched = user_folder.join(format!("{}.tmp", backup.name()));
+    let restored = user_folder.join(format!("{}.anki2.tmp", backup.id));
+    let decoded = fetch_and_decode(storage, username, backup, &fetched, &restored);
+    let result = decoded.and_then(|_| {
+        let col = collection_path(user_folder);
+        let mut conn = Connection::open(&col)?;
+        conn.restore(
+            DatabaseName::Main,
+            &restored,
+            None::<fn(rusqlite::backup::Progress)>,
+        )?;
+        conn.close().map_err(|(_, e)| e)?;
+        bump_schema_modified(&col)?;
+        Ok(())
+    });
+    let _ = fs::remove_file(&fetched);
+    let _ = fs::remove_file(&restored);
+    result
+}
+
+/// copy backup out of storage to fetched and decompress it to restored
+fn fetch_and_decode(
+    storage: &dyn Storage,
+    username: &str,
+    backup: &Backup,
+    fetched: &Path,
+    restored: &Path,
+) -> Result<(), ApplicationError> {
+    if !storage.get_file(Area::Backup, username, &backup.name(), fetched)? {
+        return Err(ApplicationError::ValueNotFound(format!(
+            "backup {} is gone",
+            backup.id
+        )));
+    }
+    zstd::stream::copy_decode(File::open(fetched)?, File::create(restored)?)?;
+    Ok(())
+}
+
+fn find_backup(
+    storage: &dyn Storage,
+    username: &str,
+    id: &str,
+) -> Result<Backup, ApplicationError> {
+    list_backups(storage, username)?
+        .into_iter()
+        .find(|b| b.id == id)
+        .ok_or_else(|| ApplicationError::ValueNotFound(format!("no backup {id}")))
+}
+
+/// handle the `backup` subcommand
+pub fn backup_manage(cmd: &BackupCommand, config: &Config) -> Result<(), ApplicationError> {
+    let username = match cmd {
+        BackupCommand::List { username }
+        | BackupCommand::Create { username }
+        | BackupCommand::Restore { username, .. } => username,
+    };
+    if !user_exists(username, config.auth_db_path())? {
+        return Err(ApplicationError::ValueNotFound(format!(
+            "no such user {username}"
+        )));
+    }
+    let user_folder = Path::new(&config.data_root_path()).join(username);
+    let storage = storage::from_config(config)?;
+    let storage = storage.as_ref();
+    match cmd {
+        BackupCommand::List { .. } => {
+            for b in list_backups(storage, username)? {
+                println!(
+                    "{}\t{}\t{} bytes",
+                    b.id,
+                    b.created.format("%Y-%m-%d %H:%M:%S"),
+                    b.size
+                );
+            }
+        }
+        BackupCommand::Create { .. } => {
+            let guard = MaintenanceGuard::lock_out(&user_folder)?;
+            let backup = backup_collection(storage, None, username, &user_folder, config.backup())?;
+            drop(guard);
+            match backup {
+                Some(b) => println!("created backup {}", b.id),
+                None => println!("user {username} has no collection yet"),
+            }
+        }
+        BackupCommand::Restore { id, .. } => {
+            let backup = find_backup(storage, username, id)?;
+            let guard = MaintenanceGuard::lock_out(&user_folder)?;
+            // the current state may be the one worth keeping after all
+            if let Some(b) = create_backup(storage, None, username, &user_folder)? {
+                println!("backed up current collection as {}", b.id);
+            }
+            restore_backup(storage, username, &user_folder, &backup)?;
+            mark_restored(&user_folder)?;
+            drop(guard);
+            println!(
+                "restored backup {id} of user {username},clients will be asked for a full sync"
+            );
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,180 @@
+//! check the collection of a user on the server,as Check Database does in the
+//! clients.
+//!
+//! The collection is opened with anki,which runs its database check
+//! (integrity,missing notetypes and templates,invalid card ordinals and card
+//! properties,...) and fixes what it finds.Objects whose usn a client would
+//! never be sent (pending,or beyond the usn of the collection) are checked
+//! beforehand.Without `--fix` all of it runs on a snapshot of the collection
+//! that is thrown away afterwards,so only the report is left.With `--fix` the
+//! user is locked out of syncing while the collection itself is repaired,fixed
+//! objects get the current usn and reach clients on their next sync.Either way
+//! the running server is asked to close the collection first,see
+//! `maintenance`.
+use crate::backup::create_backup;
+use crate::collection::{collection_path, snapshot_database, COLLECTION_FILE};
+use crate::config::Config;
+use crate::error::ApplicationError;
+use crate::maintenance::{mark_restored, MaintenanceGuard};
+use crate::storage;
+use crate::user::user_exists;
+use anki::collection::CollectionBuilder;
+use rusqlite::{Connection, OptionalExtension};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// tables of the collection holding synced objects
+const USN_TABLES: [&str; 9] = [
+    "cards",
+    "notes",
+    "revlog",
+    "graves",
+    "decks",
+    "deck_config",
+    "notetypes",
+    "tags",
+    "config",
+];
+
+/// tables clients compare the row counts of in the sanity check ending a sync
+const COUNTED_TABLES: [&str; 7] = [
+    "cards",
+    "notes",
+    "revlog",
+    "graves",
+    "notetypes",
+    "decks",
+    "deck_config",
+];
+
+/// number of rows of the tables clients compare after a sync
+fn sanity_counts(conn: &Connection) -> Result<Vec<(&'static str, i64)>, rusqlite::Error> {
+    let mut counts = vec![];
+    for table in COUNTED_TABLES {
+        if has_table(conn, table)? {
+            let count = conn.query_row(&format!("SELECT count() FROM {table}"), [], |row| {
+                row.get(0)
+            })?;
+            counts.push((table, count));
+        }
+    }
+    Ok(counts)
+}
+
+fn has_table(conn: &Connection, table: &str) -> Result<bool, rusqlite::Error> {
+    conn.query_row(
+        "SELECT 1 FROM sqlite_master WHERE type='table' AND name=?",
+        [table],
+        |_| Ok(()),
+    )
+    .optional()
+    .map(|t| t.is_some())
+}
+
+/// give objects no client would be sent the usn of the collection,return a
+/// line per table holding any,worded as fixed or as found only when
+/// checking a copy.
+///
+/// a server only records objects with the usn of the sync that brought them,
+/// objects pending as on a client (-1) or newer than the collection are
+/// skipped by every sync,which ends in a failed sanity check.
+fn fix_usns(conn: &Connection, fix: bool) -> Result<Vec<String>, rusqlite::Error> {
+    let usn: i64 = conn.query_row("SELECT usn FROM col", [], |row| row.get(0))?;
+    let mut problems = vec![];
+    for table in USN_TABLES {
+        if !has_table(conn, table)? {
+            continue;
+        }
+        let fixed = conn.execute(
+            &format!("UPDATE {table} SET usn=?1 WHERE usn=-1 OR usn>?1"),
+            [usn],
+        )?;
+        if fixed > 0 {
+            let verb = if fix { "Fixed" } else { "Found" };
+            problems.push(format!(
+                "{verb} {fixed} {table} with a usn no client would be sent."
+            ));
+        }
+    }
+    Ok(problems)
+}
+
+/// check and repair the collection at path,return the problems fixed and the
+/// row counts clients compare after a sync.fix is false for a copy of the
+/// collection that only serves the report.
+pub fn check_collection(
+    path: &Path,
+    fix: bool,
+) -> Result<(Vec<String>, Vec<(&'static str, i64)>), ApplicationError> {
+    let conn = Connection::open(path)?;
+    let mut problems = fix_usns(&conn, fix)?;
+    conn.close().map_err(|(_, e)| e)?;
+
+    let mut col = CollectionBuilder::new(path).set_server(true).build()?;
+    // the inherent method of the same name is private to anki
+    let output = anki::services::CollectionService::check_database(&mut col)?;
+    col.close(None)?;
+    problems.extend(output.problems);
+
+    let conn = Connection::open(path)?;
+    let counts = sanity_counts(&conn)?;
+    Ok((problems, counts))
+}
+
+/// handle `check`
+pub fn check_user(config: &Config, username: &str, fix: bool) -> Result<(), ApplicationError> {
+    if !user_exists(username, config.auth_db_path())? {
+        return Err(ApplicationError::ValueNotFound(format!(
+            "no such user {username}"
+        )));
+    }
+    let user_folder = PathBuf::from(config.data_root_path()).join(username);
+    let storage = storage::from_config(config)?;
+    let storage = storage.as_ref();
+    let col = collection_path(&user_folder);
+    if !col.exists() {
+        println!("user {username} has no collection yet");
+        return Ok(());
+    }
+
+    let (problems, counts) = if fix {
+        let guard = MaintenanceGuard::lock_out(&user_folder)?;
+        // the current state may be the one worth keeping after all
+        if let Some(b) = create_backup(storage, None, username, &user_folder)? {
+            println!("backed up current collection as {}", b.id);
+        }
+        let result = check_collection(&col, true)?;
+        mark_restored(&user_folder)?;
+        drop(guard);
+        result
+    } else {
+        let snapshot = user_folder.join(format!("{COLLECTION_FILE}.check.tmp"));
+        // syncs only wait while the snapshot is taken
+        let guard = MaintenanceGuard::lock_out(&user_folder)?;
+        snapshot_database(&col, &snapshot)?;
+        drop(guard);
+        let result = check_collection(&snapshot, false);
+        for path in [snapshot.clone(), snapshot.with_extension("tmp-wal")] {
+            let _ = fs::remove_file(path);
+        }
+        result?
+    };
+
+    let counts: Vec<_> = counts.iter().map(|(t, c)| format!("{t} {c}")).collect();
+    println!("{username}: {}", counts.join(","));
+    if problems.is_empty() {
+        println!("no problems found");
+        return Ok(());
+    }
+    for problem in &problems {
+        println!("\t{problem}");
+    }
+    match fix {
+        true => println!("fixed {} problems", problems.len()),
+        false => println!(
+            "found {} problems (anki words them as fixed,they were only fixed on a copy),run with --fix to repair the collection",
+            problems.len()
+        ),
+    }
+    Ok(())
+}
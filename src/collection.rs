@@ -0,0 +1,103 @@
+//! helpers working on the collection file stored in a user folder.
+use crate::error::ApplicationError;
+use crate::server::{close_user_collection, SyncServer};
+use crate::spool::SpooledBody;
+use anki::sync::http_server::user::User;
+use rusqlite::{Connection, OpenFlags};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// file name of the collection inside a user folder,as used by anki's `User`
+pub const COLLECTION_FILE: &str = "collection.anki2";
+
+pub fn collection_path(user_folder: &Path) -> PathBuf {
+    user_folder.join(COLLECTION_FILE)
+}
+
+/// size of the collection including its write-ahead log,0 if there's none yet.
+pub fn collection_size(user_folder: &Path) -> u64 {
+    let col = collection_path(user_folder);
+    let wal = col.with_extension("anki2-wal");
+    [col, wal]
+        .iter()
+        .filter_map(|p| std::fs::metadata(p).ok())
+        .map(|m| m.len())
+        .sum()
+}
+
+/// bump the schema modification time of a collection that is not open,so that
+/// every client has to do a full sync on its next sync.
+pub fn bump_schema_modified(col_path: &Path) -> Result<(), rusqlite::Error> {
+    let now_ms = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as i64)
+        .unwrap_or_default();
+    let conn = Connection::open(col_path)?;
+    conn.execute("UPDATE col SET scm=?1, mod=?1", [now_ms])?;
+    conn.close().map_err(|(_, e)| e)?;
+    Ok(())
+}
+
+/// write a consistent copy of the database at src to dst.
+///
+/// the database may be open meanwhile,`VACUUM INTO` copies its last committed
+/// state.
+pub fn snapshot_database(src: &Path, dst: &Path) -> Result<(), rusqlite::Error> {
+    // VACUUM INTO refuses to overwrite,leftovers of an interrupted snapshot
+    let _ = std::fs::remove_file(dst);
+    let conn = Connection::open(src)?;
+    conn.execute("VACUUM INTO ?", [dst.to_string_lossy()])?;
+    conn.close().map_err(|(_, e)| e)?;
+    Ok(())
+}
+
+/// answer of anki to an upload that is not a valid collection,shown by clients
+const CORRUPT_UPLOAD: &str =
+    "Your upload was corrupt. Please use Check Database, or restore from backup.";
+
+/// whether path holds a sound collection
+fn check_integrity(path: &Path) -> Result<(), rusqlite::Error> {
+    let conn = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+    let result: String = conn.query_row("PRAGMA integrity_check", [], |row| row.get(0))?;
+    if result != "ok" {
+        return Err(rusqlite::Error::InvalidQuery);
+    }
+    conn.query_row("SELECT count() FROM col", [], |row| row.get::<_, i64>(0))?;
+    Ok(())
+}
+
+/// replace the collection of user with a full upload,what anki's `upload`
+/// does,but taking the upload from disk.return the answer to the client.
+pub fn receive_upload(user: &mut User, upload: SpooledBody) -> Result<Vec<u8>, ApplicationError> {
+    close_user_collection(user);
+    if let Err(e) = check_integrity(upload.path()) {
+        log::warn!(
+            "upload of user {} is not a valid collection: {e}",
+            user.name
+        );
+        return Ok(CORRUPT_UPLOAD.as_bytes().to_vec());
+    }
+    upload.persist(&collection_path(&user.folder))?;
+    Ok(b"OK".to_vec())
+}
+
+/// close the collection of user on the running server and force a full sync.
+///
+/// the user stays locked meanwhile,so no sync can reopen the collection.
+pub fn force_full_sync(server: &SyncServer, username: &str) -> Result<(), ApplicationError> {
+    let forced = server.with_user(username, |user| {
+        close_user_collection(user);
+        let col_path = collection_path(&user.folder);
+        if col_path.exists() {
+            bump_schema_modified(&col_path)?;
+            log::info!("forced a full sync for user {username}");
+        }
+        Ok::<_, ApplicationError>(())
+    });
+    match forced {
+        Some(result) => result,
+        None => Err(ApplicationError::ValueNotFound(format!(
+            "user {username} is not loaded"
+        ))),
+    }
+}
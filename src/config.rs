@@ -1,15 +1,57 @@
 use crate::error::ApplicationError;
+use crate::proxy::TrustedProxies;
 use serde::{Deserialize, Serialize};
+use std::env;
 use std::fs::File;
 use std::io::Read;
 use std::path::Path;
+use std::time::Duration;
+use toml::value::{Table, Value};
+
+/// prefix of env vars overriding config values,`ANKISYNCD_SECTION__KEY` or
+/// `ANKISYNCD_SECTION__SUBSECTION__KEY`
+const ENV_PREFIX: &str = "ANKISYNCD_";
+
+/// env vars of older versions and the values they still set,overridden by
+/// `ANKISYNCD_SECTION__KEY` vars
+const LEGACY_ENV_VARS: [(&str, &str); 3] = [
+    ("MAX_SYNC_PAYLOAD_MEGS", "limits.max_sync_payload_megs"),
+    ("ANKISYNCD_USERNAME", "account.username"),
+    ("ANKISYNCD_PASSWORD", "account.password"),
+];
+
+/// values masked when printing the effective config
+const SECRET_KEYS: [&str; 3] = [
+    "admin.token",
+    "account.password",
+    "storage.media.secret_key",
+];
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Config {
     listen: ConfigAddr,
     paths: ConfigPaths,
     encryption: Option<ConfigCert>,
-    #[cfg(feature = "account")]
+    #[serde(default)]
+    hashing: ConfigHashing,
+    #[serde(default)]
+    admin: ConfigAdmin,
+    #[serde(default)]
+    backup: ConfigBackup,
+    #[serde(default)]
+    shutdown: ConfigShutdown,
+    #[serde(default)]
+    health: ConfigHealth,
+    #[serde(default)]
+    metrics: ConfigMetrics,
+    #[serde(default)]
+    logging: ConfigLogging,
+    #[serde(default)]
+    security: ConfigSecurity,
+    #[serde(default)]
+    limits: ConfigLimits,
+    #[serde(default)]
+    storage: ConfigStorage,
     pub account: Option<Account>,
 }
 
@@ -19,7 +61,16 @@ impl Default for Config {
             listen: ConfigAddr::default(),
             paths: ConfigPaths::default(),
             encryption: Some(ConfigCert::default()),
-            #[cfg(feature = "account")]
+            hashing: ConfigHashing::default(),
+            admin: ConfigAdmin::default(),
+            backup: ConfigBackup::default(),
+            shutdown: ConfigShutdown::default(),
+            health: ConfigHealth::default(),
+            metrics: ConfigMetrics::default(),
+            logging: ConfigLogging::default(),
+            security: ConfigSecurity::default(),
+            limits: ConfigLimits::default(),
+            storage: ConfigStorage::default(),
             account: None,
         }
     }
@@ -30,7 +81,9 @@ impl Config {
         let mut file = File::open(path)?;
         let mut config_string = String::new();
         file.read_to_string(&mut config_string)?;
-        let c = toml::from_str(&config_string)?;
+        let c: Config = toml::from_str(&config_string)?;
+        // refuse to start with proxies that would not be recognized
+        c.trusted_proxies()?;
         Ok(c)
     }
 
@@ -50,6 +103,14 @@ impl Config {
         format!("{}:{}", &self.listen.host, self.listen.port)
     }
 
+    pub fn trusted_proxies(&self) -> Result<TrustedProxies, ApplicationError> {
+        TrustedProxies::parse(&self.listen.trusted_proxies)
+    }
+
+    pub fn proxy_protocol(&self) -> bool {
+        self.listen.proxy_protocol
+    }
+
     pub fn data_root_path(&self) -> String {
         format!("{}/collections/", self.paths.root_dir)
     }
@@ -58,6 +119,21 @@ impl Config {
         format!("{}/auth.db", self.paths.root_dir)
     }
 
+    /// folder holding the collection backups,one subfolder per user
+    pub fn backup_root_path(&self) -> String {
+        format!("{}/backups", self.paths.root_dir)
+    }
+
+    /// content-addressed store of the media files shared by users
+    pub fn media_store_path(&self) -> String {
+        format!("{}/media-store", self.paths.root_dir)
+    }
+
+    /// database of the server itself,i.e. the sync history
+    pub fn server_db_path(&self) -> String {
+        format!("{}/server.db", self.paths.root_dir)
+    }
+
     // pub fn session_db_path(&self) -> String {
     //     format!("{}/session.db", self.paths.root_dir)
     // }
@@ -65,12 +141,224 @@ impl Config {
     pub fn encryption_config(&self) -> Option<&ConfigCert> {
         self.encryption.as_ref()
     }
+
+    pub fn hashing(&self) -> &ConfigHashing {
+        &self.hashing
+    }
+
+    /// token granting access to the admin api,`None` if the api is disabled.
+    pub fn admin_token(&self) -> Option<&str> {
+        Some(self.admin.token.as_str()).filter(|t| !t.is_empty())
+    }
+
+    pub fn backup(&self) -> &ConfigBackup {
+        &self.backup
+    }
+
+    /// how long to wait for sync sessions in progress on shutdown
+    pub fn shutdown_grace_period(&self) -> Duration {
+        Duration::from_secs(self.shutdown.grace_period_secs)
+    }
+
+    /// free disk space below which `/readyz` reports the server as not ready
+    pub fn min_free_disk_bytes(&self) -> u64 {
+        self.health.min_free_disk_megs * 1024 * 1024
+    }
+
+    pub fn metrics_enabled(&self) -> bool {
+        self.metrics.enabled
+    }
+
+    /// whether metrics include storage per user
+    pub fn metrics_per_user(&self) -> bool {
+        self.metrics.per_user
+    }
+
+    /// listen address of the metrics endpoint,`None` to serve it on the sync
+    /// listener.
+    pub fn metrics_listen_on(&self) -> Option<&str> {
+        Some(self.metrics.listen.as_str()).filter(|l| !l.is_empty())
+    }
+
+    pub fn logging(&self) -> &ConfigLogging {
+        &self.logging
+    }
+
+    pub fn security(&self) -> &ConfigSecurity {
+        &self.security
+    }
+
+    /// size limit of a sync request as sent,in megabytes
+    pub fn max_sync_payload_megs(&self) -> u64 {
+        self.limits.max_sync_payload_megs
+    }
+
+    pub fn storage(&self) -> &ConfigStorage {
+        &self.storage
+    }
+
+    /// overrides from env vars as (`section.key`,value),legacy vars first so
+    /// that `ANKISYNCD_SECTION__KEY` vars win over them.Vars naming no config
+    /// value are skipped with a warning,as the environment may hold vars meant
+    /// for other versions.
+    pub fn env_overrides() -> Vec<(String, String)> {
+        let skeleton = Value::try_from(Config::skeleton()).ok();
+        let mut overrides: Vec<(String, String)> = LEGACY_ENV_VARS
+            .iter()
+            .filter_map(|(var, key)| {
+                let value = env::var(var).ok().filter(|v| !v.is_empty())?;
+                Some((key.to_string(), value))
+            })
+            .collect();
+        let mut vars: Vec<(String, String)> = env::vars()
+            .filter_map(|(name, value)| {
+                let path = name.strip_prefix(ENV_PREFIX)?;
+                if !path.contains("__") {
+                    return None;
+                }
+                let path = path.to_lowercase().replace("__", ".");
+                let known = skeleton
+                    .as_ref()
+                    .and_then(|s| path.split('.').try_fold(s, |v, k| v.get(k)))
+                    .is_some();
+                if !known {
+                    // the logger is not set up before the config is read
+                    eprintln!("Warning: ignoring env var {name},{path} is no config value");
+                    return None;
+                }
+                Some((path, value))
+            })
+            .collect();
+        vars.sort();
+        overrides.extend(vars);
+        overrides
+    }
+
+    /// set the values of overrides given as (`section.key`,value),later ones
+    /// win.A value is parsed as the type of the field it sets,lists also
+    /// accept comma-separated values.
+    pub fn with_overrides(self, overrides: &[(String, String)]) -> Result<Self, ApplicationError> {
+        if overrides.is_empty() {
+            return Ok(self);
+        }
+        let skeleton = Value::try_from(Config::skeleton())?;
+        let mut value = Value::try_from(&self)?;
+        for (path, raw) in overrides {
+            let keys: Vec<&str> = path.split('.').collect();
+            let (key, sections) = match keys.split_last() {
+                Some((key, sections)) if !sections.is_empty() => (key, sections),
+                _ => {
+                    return Err(ApplicationError::ParseConfig(format!(
+                        "{path}: expected section.key"
+                    )))
+                }
+            };
+            let expected = keys
+                .iter()
+                .try_fold(&skeleton, |v, k| v.get(k))
+                .ok_or_else(|| {
+                    ApplicationError::ParseConfig(format!("{path}: no such config value"))
+                })?;
+            let parsed = parse_value(expected, raw)
+                .map_err(|e| ApplicationError::ParseConfig(format!("{path}: {e}")))?;
+            let mut table = value
+                .as_table_mut()
+                .ok_or_else(|| ApplicationError::ParseConfig("config is not a table".into()))?;
+            for section in sections {
+                table = table
+                    .entry(section.to_string())
+                    .or_insert_with(|| Value::Table(Table::new()))
+                    .as_table_mut()
+                    .ok_or_else(|| {
+                        ApplicationError::ParseConfig(format!("{section} is not a section"))
+                    })?;
+            }
+            table.insert(key.to_string(), parsed);
+        }
+        let c: Config = value
+            .try_into()
+            .map_err(|e| ApplicationError::ParseConfig(format!("override: {e}")))?;
+        c.trusted_proxies()?;
+        Ok(c)
+    }
+
+    /// the config as toml with secrets masked,for `--print-effective-config`
+    pub fn to_masked_string(&self) -> Result<String, ApplicationError> {
+        let mut value = Value::try_from(self)?;
+        for path in SECRET_KEYS {
+            let secret = path.split('.').try_fold(&mut value, |v, k| v.get_mut(k));
+            if let Some(v) = secret {
+                if matches!(v.as_str(), Some(s) if !s.is_empty()) {
+                    *v = Value::String("********".to_string());
+                }
+            }
+        }
+        Ok(toml::to_string(&value)?)
+    }
+
+    /// the default config with every optional section present,tells the
+    /// type of each value that may be overridden
+    fn skeleton() -> Config {
+        Config {
+            account: Some(Account {
+                username: Some(String::new()),
+                password: Some(String::new()),
+            }),
+            storage: ConfigStorage {
+                media: Some(ConfigS3::default()),
+                ..ConfigStorage::default()
+            },
+            ..Config::default()
+        }
+    }
+}
+
+/// parse raw as the type of expected
+fn parse_value(expected: &Value, raw: &str) -> Result<Value, String> {
+    let trimmed = raw.trim();
+    match expected {
+        Value::String(_) => Ok(Value::String(raw.to_string())),
+        Value::Integer(_) => trimmed
+            .parse()
+            .map(Value::Integer)
+            .map_err(|e| format!("{raw:?} is not an integer: {e}")),
+        Value::Float(_) => trimmed
+            .parse()
+            .map(Value::Float)
+            .map_err(|e| format!("{raw:?} is not a number: {e}")),
+        Value::Boolean(_) => trimmed
+            .parse()
+            .map(Value::Boolean)
+            .map_err(|_| format!("{raw:?} is not true or false")),
+        Value::Array(_) if trimmed.starts_with('[') => {
+            toml::from_str::<Table>(&format!("v = {trimmed}"))
+                .ok()
+                .and_then(|mut t| t.remove("v"))
+                .ok_or_else(|| format!("{raw:?} is not a list"))
+        }
+        Value::Array(_) => Ok(Value::Array(
+            trimmed
+                .split(',')
+                .map(str::trim)
+                .filter(|s| !s.is_empty())
+                .map(|s| Value::String(s.to_string()))
+                .collect(),
+        )),
+        _ => Err("cannot be overridden".to_string()),
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ConfigAddr {
     pub host: String,
     pub port: u16,
+    /// reverse proxies (CIDRs or addresses) whose forwarding headers are trusted
+    #[serde(default)]
+    pub trusted_proxies: Vec<String>,
+    /// whether trusted proxies start their connections with a PROXY protocol
+    /// header
+    #[serde(default)]
+    pub proxy_protocol: bool,
 }
 
 impl Default for ConfigAddr {
@@ -78,6 +366,8 @@ impl Default for ConfigAddr {
         ConfigAddr {
             host: "0.0.0.0".to_string(),
             port: 27701,
+            trusted_proxies: vec![],
+            proxy_protocol: false,
         }
     }
 }
@@ -102,14 +392,246 @@ pub struct ConfigCert {
     pub key_file: String,
 }
 
-/// account in config file
-#[cfg(feature = "account")]
+/// Argon2id cost parameters used when storing user passwords.
+///
+/// Changing them only affects passwords hashed afterwards, existing hashes keep
+/// the parameters they were created with.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ConfigHashing {
+    /// memory size in KiB
+    pub memory_cost: u32,
+    /// number of iterations
+    pub time_cost: u32,
+    /// degree of parallelism
+    pub parallelism: u32,
+}
+
+impl Default for ConfigHashing {
+    fn default() -> Self {
+        ConfigHashing {
+            memory_cost: 19456,
+            time_cost: 2,
+            parallelism: 1,
+        }
+    }
+}
+
+/// admin api settings,the api is disabled while token is empty
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+pub struct ConfigAdmin {
+    pub token: String,
+}
+
+/// server-side collection backups and their retention.
+///
+/// The newest `keep_last` backups are always kept,besides them the newest
+/// backup of each of the last `keep_daily` days,`keep_weekly` weeks and
+/// `keep_monthly` months that have one.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ConfigBackup {
+    pub enabled: bool,
+    /// minimum number of hours between backups taken after normal syncs
+    pub interval_hours: u64,
+    pub keep_last: usize,
+    pub keep_daily: usize,
+    pub keep_weekly: usize,
+    pub keep_monthly: usize,
+}
+
+impl Default for ConfigBackup {
+    fn default() -> Self {
+        ConfigBackup {
+            enabled: true,
+            interval_hours: 24,
+            keep_last: 3,
+            keep_daily: 7,
+            keep_weekly: 4,
+            keep_monthly: 6,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ConfigShutdown {
+    /// seconds to wait for sync sessions in progress before stopping anyway
+    pub grace_period_secs: u64,
+}
+
+impl Default for ConfigShutdown {
+    fn default() -> Self {
+        ConfigShutdown {
+            grace_period_secs: 30,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ConfigHealth {
+    /// free disk space in megabytes required on the data root to be ready
+    pub min_free_disk_megs: u64,
+}
+
+impl Default for ConfigHealth {
+    fn default() -> Self {
+        ConfigHealth {
+            min_free_disk_megs: 100,
+        }
+    }
+}
+
+/// prometheus metrics under `/metrics`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ConfigMetrics {
+    pub enabled: bool,
+    /// address (host:port) to serve metrics on,empty for the sync listener
+    pub listen: String,
+    /// export storage per user,labelled with usernames
+    pub per_user: bool,
+}
+
+impl Default for ConfigMetrics {
+    fn default() -> Self {
+        ConfigMetrics {
+            enabled: false,
+            listen: String::new(),
+            per_user: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ConfigLogging {
+    /// `text` or `json`,one object per line
+    pub format: String,
+}
+
+impl Default for ConfigLogging {
+    fn default() -> Self {
+        ConfigLogging {
+            format: "text".to_string(),
+        }
+    }
+}
+
+/// throttling of logins,limits of 0 are disabled.
+///
+/// Attempts and failures are counted per user and per client ip within the
+/// last `window_secs`.Too many failures lock the user or ip out for
+/// `lockout_secs`,doubled on each further lockout up to `max_lockout_secs`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ConfigSecurity {
+    pub enabled: bool,
+    pub window_secs: u64,
+    pub max_attempts_per_user: u32,
+    pub max_attempts_per_ip: u32,
+    pub max_failures_per_user: u32,
+    pub max_failures_per_ip: u32,
+    pub lockout_secs: u64,
+    pub max_lockout_secs: u64,
+}
+
+impl Default for ConfigSecurity {
+    fn default() -> Self {
+        ConfigSecurity {
+            enabled: true,
+            window_secs: 900,
+            max_attempts_per_user: 20,
+            max_attempts_per_ip: 60,
+            max_failures_per_user: 5,
+            max_failures_per_ip: 20,
+            lockout_secs: 60,
+            max_lockout_secs: 3600,
+        }
+    }
+}
+
+/// size limits of sync requests
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ConfigLimits {
+    /// size limit of a sync request as sent in megabytes,three times as much
+    /// once decompressed
+    pub max_sync_payload_megs: u64,
+}
+
+impl Default for ConfigLimits {
+    fn default() -> Self {
+        ConfigLimits {
+            max_sync_payload_megs: 1000,
+        }
+    }
+}
+
+/// where collections,media files and backups are kept
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ConfigStorage {
+    /// `local`,files under root_dir
+    pub backend: String,
+    /// store identical media files of all users once,hard-linked into the
+    /// media folders,only with local media
+    pub dedup_media: bool,
+    /// keep media files in S3-compatible object storage instead
+    pub media: Option<ConfigS3>,
+}
+
+impl Default for ConfigStorage {
+    fn default() -> Self {
+        ConfigStorage {
+            backend: "local".to_string(),
+            dedup_media: false,
+            media: None,
+        }
+    }
+}
+
+/// a bucket of S3-compatible object storage,objects are named
+/// `<prefix><username>/<file name>`.
+///
+/// keys left empty are read from the env vars `AWS_ACCESS_KEY_ID` and
+/// `AWS_SECRET_ACCESS_KEY`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ConfigS3 {
+    /// i.e. `https://s3.eu-central-1.amazonaws.com` or `http://minio:9000`
+    pub endpoint: String,
+    pub bucket: String,
+    pub prefix: String,
+    pub region: String,
+    pub access_key: String,
+    pub secret_key: String,
+    /// address the bucket as `<endpoint>/<bucket>` rather than as
+    /// `<bucket>.<host>`,needed by most self-hosted servers
+    pub path_style: bool,
+}
+
+impl Default for ConfigS3 {
+    fn default() -> Self {
+        ConfigS3 {
+            endpoint: String::new(),
+            bucket: String::new(),
+            prefix: String::new(),
+            region: "us-east-1".to_string(),
+            access_key: String::new(),
+            secret_key: String::new(),
+            path_style: true,
+        }
+    }
+}
+
+/// account added on startup if it does not exist yet
 #[derive(Debug, Clone, Serialize, Deserialize, Default)]
 pub struct Account {
     username: Option<String>,
     password: Option<String>,
 }
-#[cfg(feature = "account")]
 impl Account {
     pub fn username(&self) -> Option<String> {
         // return Some("") if field is item="",so use filter to transform Some("") to None
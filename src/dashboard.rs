@@ -0,0 +1,246 @@
+//! server-rendered admin dashboard under `/admin`.
+//!
+//! It uses the same token as the admin api,browsers ask for it through http
+//! basic auth (any username,the token as password).The page lists users with
+//! their last sync,collection and media usage,and recent errors,and offers
+//! password reset and forcing a full sync.Its forms carry a token only the
+//! dashboard page knows,so other sites cannot submit them with the basic auth
+//! credentials the browser sends along.
+use crate::admin::{internal, reload_users, AdminAuth};
+use crate::collection::{collection_size, force_full_sync};
+use crate::config::Config;
+use crate::error::{recent_errors, ApplicationError};
+use crate::media::media_usage;
+use crate::server::{off_worker, SyncServer};
+use crate::sync_log::last_syncs;
+use crate::user::{constant_time_eq, set_password_for_user, user_exists, user_infos, UserError};
+use actix_web::http::header::LOCATION;
+use actix_web::{web, HttpRequest, HttpResponse};
+use chrono::{Local, TimeZone};
+use lazy_static::lazy_static;
+use serde::Deserialize;
+use std::fmt::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+#[derive(Deserialize)]
+pub struct DashboardQuery {
+    msg: Option<String>,
+}
+
+lazy_static! {
+    // token of the forms of the dashboard,new on every start
+    static ref FORM_TOKEN: String = hex::encode(rand::random::<[u8; 32]>());
+}
+
+#[derive(Deserialize)]
+pub struct PasswordForm {
+    password: String,
+    token: String,
+}
+
+#[derive(Deserialize)]
+pub struct FullSyncForm {
+    token: String,
+}
+
+fn escape_html(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+fn format_time(secs: i64) -> String {
+    Local
+        .timestamp_opt(secs, 0)
+        .single()
+        .map(|t| t.format("%Y-%m-%d %H:%M:%S").to_string())
+        .unwrap_or_default()
+}
+
+fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KB", "MB", "GB", "TB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{bytes} B")
+    } else {
+        format!("{size:.1} {}", UNITS[unit])
+    }
+}
+
+/// whether a form was submitted from the dashboard page
+fn valid_form_token(token: &str) -> bool {
+    constant_time_eq(token.as_bytes(), FORM_TOKEN.as_bytes())
+}
+
+fn forbidden() -> HttpResponse {
+    HttpResponse::Forbidden().body("form token missing or outdated,reload the dashboard")
+}
+
+fn redirect(msg: &str) -> HttpResponse {
+    let msg: String = urlparse::quote(msg, b"").unwrap_or_default();
+    HttpResponse::SeeOther()
+        .insert_header((LOCATION, format!("/admin?msg={msg}")))
+        .finish()
+}
+
+/// table rows of the users of auth db,their last sync is taken from the sync
+/// log of server db.
+fn user_rows(
+    auth_db: &str,
+    server_db: &str,
+    base_folder: &Path,
+) -> Result<String, ApplicationError> {
+    let users = user_infos(auth_db).map_err(internal)?;
+    let last = last_syncs(server_db)?;
+    let mut rows = String::new();
+    for u in &users {
+        let folder = base_folder.join(&u.username);
+        let last_sync = last
+            .get(&u.username)
+            .map(|t| format_time(t / 1000))
+            .unwrap_or_else(|| "never".to_string());
+        let (media_count, media_size) = match media_usage(&folder) {
+            Ok((count, size)) => (count.to_string(), format_size(size)),
+            Err(e) => {
+                log::error!("failed to read media db of user {}: {e}", u.username);
+                ("?".to_string(), "?".to_string())
+            }
+        };
+        let name = escape_html(&u.username);
+        let path_name = escape_html(&urlparse::quote(&u.username, b"").unwrap_or_default());
+        let status = if u.disabled { "disabled" } else { "active" };
+        let _ = write!(
+            rows,
+            r#"<tr><td>{name}</td><td>{status}</td><td>{last_sync}</td><td>{col_size}</td><td>{media_count}</td><td>{media_size}</td>
+<td><form method="post" action="/admin/users/{path_name}/password"><input type="hidden" name="token" value="{token}"><input type="password" name="password" required placeholder="new password"><button>Reset password</button></form></td>
+<td><form method="post" action="/admin/users/{path_name}/full-sync" onsubmit="return confirm('Force a full sync?')"><input type="hidden" name="token" value="{token}"><button>Force full sync</button></form></td></tr>
+"#,
+            col_size = format_size(collection_size(&folder)),
+            token = *FORM_TOKEN,
+        );
+    }
+    Ok(rows)
+}
+
+pub async fn dashboard(
+    _: AdminAuth,
+    query: web::Query<DashboardQuery>,
+    auth_db: web::Data<String>,
+    base_folder: web::Data<PathBuf>,
+    config: web::Data<Config>,
+) -> Result<HttpResponse, ApplicationError> {
+    // reads auth db and server db and walks the folder of every user
+    let server_db = config.server_db_path();
+    let rows = off_worker(move || user_rows(&auth_db, &server_db, &base_folder)).await??;
+    let mut errors = String::new();
+    for (time, msg) in recent_errors() {
+        let _ = writeln!(
+            errors,
+            "<tr><td>{}</td><td>{}</td></tr>",
+            format_time(time),
+            escape_html(&msg)
+        );
+    }
+    if errors.is_empty() {
+        errors.push_str("<tr><td colspan=\"2\">no errors</td></tr>");
+    }
+    let notice = query
+        .msg
+        .as_deref()
+        .map(|m| format!("<p class=\"notice\">{}</p>", escape_html(m)))
+        .unwrap_or_default();
+    let body = format!(
+        r#"<!DOCTYPE html>
+<html><head><meta charset="utf-8"><title>Anki Sync Server</title>
+<style>
+body {{ font-family: sans-serif; margin: 2em; }}
+table {{ border-collapse: collapse; margin-bottom: 2em; }}
+th, td {{ border: 1px solid #ccc; padding: 0.3em 0.6em; text-align: left; }}
+form {{ margin: 0; }}
+.notice {{ background: #eef; padding: 0.5em; }}
+</style></head>
+<body>
+<h1>Anki Sync Server</h1>
+{notice}
+<h2>Users</h2>
+<table>
+<tr><th>User</th><th>Status</th><th>Last sync</th><th>Collection</th><th>Media files</th><th>Media size</th><th></th><th></th></tr>
+{rows}</table>
+<h2>Recent errors</h2>
+<table>
+<tr><th>Time</th><th>Error</th></tr>
+{errors}</table>
+</body></html>"#
+    );
+    Ok(HttpResponse::Ok()
+        .content_type("text/html; charset=utf-8")
+        .body(body))
+}
+
+pub async fn reset_password(
+    _: AdminAuth,
+    req: HttpRequest,
+    username: web::Path<String>,
+    form: web::Form<PasswordForm>,
+    auth_db: web::Data<String>,
+    config: web::Data<Config>,
+) -> Result<HttpResponse, ApplicationError> {
+    let PasswordForm { password, token } = form.into_inner();
+    if !valid_form_token(&token) {
+        return Ok(forbidden());
+    }
+    let username = username.into_inner();
+    if password.is_empty() {
+        return Ok(redirect("password must not be empty"));
+    }
+    let (name, hashing) = (username.clone(), config.hashing().clone());
+    let changed = off_worker(move || {
+        let exists = user_exists(&name, auth_db.as_str())?;
+        if exists {
+            set_password_for_user(&name, &password, auth_db.as_str(), &hashing)?;
+        }
+        Ok::<_, UserError>(exists)
+    })
+    .await?
+    .map_err(internal)?;
+    if !changed {
+        return Ok(redirect(&format!("no such user {username}")));
+    }
+    reload_users(&req).await?;
+    log::info!("dashboard: reset password of user {username}");
+    Ok(redirect(&format!("password of {username} reset")))
+}
+
+pub async fn full_sync(
+    _: AdminAuth,
+    username: web::Path<String>,
+    form: web::Form<FullSyncForm>,
+    server: web::Data<Arc<SyncServer>>,
+) -> Result<HttpResponse, ApplicationError> {
+    if !valid_form_token(&form.token) {
+        return Ok(forbidden());
+    }
+    let username = username.into_inner();
+    let name = username.clone();
+    // locks the user and writes its collection
+    off_worker(move || force_full_sync(&server, &name)).await??;
+    Ok(redirect(&format!("full sync forced for {username}")))
+}
+
+pub fn config_dashboard(cfg: &mut web::ServiceConfig) {
+    cfg.service(web::resource("/admin").route(web::get().to(dashboard)))
+        .service(
+            web::resource("/admin/users/{username}/password").route(web::post().to(reset_password)),
+        )
+        .service(
+            web::resource("/admin/users/{username}/full-sync").route(web::post().to(full_sync)),
+        );
+}
@@ -1,8 +1,42 @@
-use rusqlite::{Connection, Result};
-/// return username and hash of each user
+use rusqlite::{Connection, OpenFlags, OptionalExtension, Result};
+/// add the columns missing from a table created by an older version,
+/// columns are given as (name,definition).
+pub(crate) fn add_missing_columns(
+    conn: &Connection,
+    table: &str,
+    columns: &[(&str, &str)],
+) -> Result<(), rusqlite::Error> {
+    let mut stmt = conn.prepare(&format!("PRAGMA table_info({table})"))?;
+    let existing = stmt
+        .query_map([], |row| row.get::<_, String>(1))?
+        .collect::<Result<Vec<_>, _>>()?;
+    for (name, definition) in columns {
+        if !existing.iter().any(|c| c == name) {
+            conn.execute(
+                &format!("ALTER TABLE {table} ADD COLUMN {name} {definition}"),
+                [],
+            )?;
+        }
+    }
+    Ok(())
+}
+/// return username and hash of each user that is allowed to sync
 pub(crate) fn fetch_users(auth_db: &str) -> Result<Option<Vec<(String, String)>>, rusqlite::Error> {
-    let sql = "SELECT username,hash FROM auth";
-    let conn = Connection::open(auth_db)?;
+    users_of(&Connection::open(auth_db)?)
+}
+
+/// as `fetch_users`,but fail if auth db does not exist instead of creating it
+pub(crate) fn fetch_existing_users(
+    auth_db: &str,
+) -> Result<Option<Vec<(String, String)>>, rusqlite::Error> {
+    let flags = OpenFlags::SQLITE_OPEN_READ_WRITE
+        | OpenFlags::SQLITE_OPEN_URI
+        | OpenFlags::SQLITE_OPEN_NO_MUTEX;
+    users_of(&Connection::open_with_flags(auth_db, flags)?)
+}
+
+fn users_of(conn: &Connection) -> Result<Option<Vec<(String, String)>>, rusqlite::Error> {
+    let sql = "SELECT username,hash FROM auth WHERE disabled=0";
     let mut stmt = conn.prepare(sql)?;
     // [Ok(TB { c: "c1", idx: 1 }), Ok(TB { c: "c2", idx: 2 })]
     let r = stmt
@@ -11,3 +45,9 @@ pub(crate) fn fetch_users(auth_db: &str) -> Result<Option<Vec<(String, String)>>
         .collect::<Vec<_>>();
     Ok(if r.is_empty() { None } else { Some(r) })
 }
+/// return the password hash of user,`None` if no such user exists or it is disabled
+pub(crate) fn fetch_hash(auth_db: &str, username: &str) -> Result<Option<String>, rusqlite::Error> {
+    let sql = "SELECT hash FROM auth WHERE username=? AND disabled=0";
+    let conn = Connection::open(auth_db)?;
+    conn.query_row(sql, [username], |row| row.get(0)).optional()
+}
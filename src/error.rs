@@ -1,5 +1,35 @@
+use actix_web::http::header::RETRY_AFTER;
 use actix_web::{HttpResponse, ResponseError};
+use lazy_static::lazy_static;
+use std::collections::VecDeque;
+use std::sync::Mutex;
 use thiserror::Error;
+
+/// number of errors kept for the admin dashboard
+const RECENT_ERRORS_CAPACITY: usize = 50;
+
+lazy_static! {
+    // most recent errors returned to clients,newest last.(unix time,message)
+    static ref RECENT_ERRORS: Mutex<VecDeque<(i64, String)>> =
+        Mutex::new(VecDeque::with_capacity(RECENT_ERRORS_CAPACITY));
+}
+
+fn record_error(msg: String) {
+    if let Ok(mut errors) = RECENT_ERRORS.lock() {
+        if errors.len() == RECENT_ERRORS_CAPACITY {
+            errors.pop_front();
+        }
+        errors.push_back((crate::hostkey::unix_now(), msg));
+    }
+}
+
+/// errors recently returned to clients,newest first
+pub(crate) fn recent_errors() -> Vec<(i64, String)> {
+    match RECENT_ERRORS.lock() {
+        Ok(errors) => errors.iter().rev().cloned().collect(),
+        Err(_) => vec![],
+    }
+}
 #[derive(Error, Debug)]
 pub enum ApplicationError {
     #[error("Sqlite error: {0}")]
@@ -15,8 +45,10 @@ pub enum ApplicationError {
     AnkiFileIoError(#[from] anki::error::FileIoError),
     #[error("Zip parsing error: {0}")]
     ZipParsing(#[from] zip::result::ZipError),
+    /// kept as text,actix errors are not `Send` and errors must be able to leave
+    /// the blocking thread pool
     #[error("Actix web error: {0}")]
-    Actix(#[from] actix_web::Error),
+    Actix(String),
     #[cfg(feature = "tls")]
     #[error("Rustls error: {0}")]
     Rustls(#[from] rustls::Error),
@@ -45,11 +77,36 @@ pub enum ApplicationError {
     SimpleServer(String),
     #[error("request url not found: {0}")]
     HttpError(#[from] anki::sync::error::HttpError),
+    /// 413
+    #[error("{0}")]
+    QuotaExceeded(String),
+    /// 503
+    #[error("{0}")]
+    ServiceUnavailable(String),
+    /// 400,a request that could not be decoded
+    #[error("Bad request: {0}")]
+    BadRequest(String),
+    /// 413,a request body over the size limit
+    #[error("Payload too large: {0}")]
+    PayloadTooLarge(String),
+    /// 501
+    #[error("Unsupported sync version: {0}")]
+    UnsupportedSyncVersion(String),
+    /// 429,retry_after in seconds
+    #[error("{message}")]
+    TooManyRequests { message: String, retry_after: u64 },
+}
+
+impl From<actix_web::Error> for ApplicationError {
+    fn from(e: actix_web::Error) -> Self {
+        ApplicationError::Actix(e.to_string())
+    }
 }
 
 /// Actix Web uses `ResponseError` for conversion of errors to a response
 impl ResponseError for ApplicationError {
     fn error_response(&self) -> HttpResponse {
+        record_error(self.to_string());
         match self {
             ApplicationError::UserError(e) => {
                 // found in anki/rslib/src/error/network.rs
@@ -61,6 +118,45 @@ impl ResponseError for ApplicationError {
                 log::error!("{}", e.to_string());
                 HttpResponse::Forbidden().finish()
             }
+            ApplicationError::QuotaExceeded(e) => {
+                log::warn!("{e}");
+                // the message is shown to the user by the client
+                HttpResponse::PayloadTooLarge()
+                    .content_type("text/plain")
+                    .body(e.clone())
+            }
+            ApplicationError::ServiceUnavailable(e) => {
+                log::warn!("{e}");
+                HttpResponse::ServiceUnavailable()
+                    .content_type("text/plain")
+                    .body(e.clone())
+            }
+            ApplicationError::BadRequest(_) => {
+                log::warn!("{self}");
+                HttpResponse::BadRequest()
+                    .content_type("text/plain")
+                    .body(self.to_string())
+            }
+            ApplicationError::PayloadTooLarge(_) => {
+                log::warn!("{self}");
+                HttpResponse::PayloadTooLarge()
+                    .content_type("text/plain")
+                    .body(self.to_string())
+            }
+            ApplicationError::UnsupportedSyncVersion(_) => {
+                log::warn!("{self}");
+                // clients ask the user to upgrade
+                HttpResponse::NotImplemented()
+                    .content_type("text/plain")
+                    .body(self.to_string())
+            }
+            ApplicationError::TooManyRequests {
+                message,
+                retry_after,
+            } => HttpResponse::TooManyRequests()
+                .insert_header((RETRY_AFTER, retry_after.to_string()))
+                .content_type("text/plain")
+                .body(message.clone()),
             e => {
                 log::error!("{}", e.to_string());
                 HttpResponse::InternalServerError().finish()
@@ -0,0 +1,133 @@
+//! liveness and readiness probes for container orchestrators.
+//!
+//! `/healthz` answers as long as the process serves requests.`/readyz` checks
+//! that auth db exists and can be read,the data root is writable,there is
+//! enough free disk space and the server is not shutting down,it answers 503
+//! if any check fails.The checks touching the disk run off the worker and fail
+//! if they do not finish within a few seconds,like on a hung network mount.
+use crate::config::Config;
+use crate::db::fetch_existing_users;
+use crate::server::off_worker;
+use crate::shutdown::Shutdown;
+use actix_web::rt::time::timeout;
+use actix_web::{get, web, HttpResponse};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// file written to the data root to check it is writable
+const PROBE_FILE: &str = ".readyz";
+/// time the checks of auth db and data root may take
+const CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Serialize)]
+struct Check {
+    ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    free_bytes: Option<u64>,
+}
+
+impl Check {
+    fn from_result<E: ToString>(result: Result<(), E>) -> Self {
+        Check {
+            ok: result.is_ok(),
+            error: result.err().map(|e| e.to_string()),
+            free_bytes: None,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct Status {
+    ok: bool,
+    checks: BTreeMap<&'static str, Check>,
+}
+
+fn check_data_root(base_folder: &Path) -> std::io::Result<()> {
+    let probe = base_folder.join(PROBE_FILE);
+    fs::write(&probe, b"ok")?;
+    fs::remove_file(probe)
+}
+
+fn check_disk_space(base_folder: &Path, min_free_bytes: u64) -> Check {
+    match fs2::available_space(base_folder) {
+        Ok(free) if free < min_free_bytes => Check {
+            ok: false,
+            error: Some(format!("less than {min_free_bytes} bytes free")),
+            free_bytes: Some(free),
+        },
+        Ok(free) => Check {
+            ok: true,
+            error: None,
+            free_bytes: Some(free),
+        },
+        Err(e) => Check::from_result(Err(e)),
+    }
+}
+
+/// checks of auth db,data root and disk space
+fn check_storage(
+    auth_db: &str,
+    base_folder: &Path,
+    min_free_bytes: u64,
+) -> Vec<(&'static str, Check)> {
+    vec![
+        (
+            "auth_db",
+            Check::from_result(fetch_existing_users(auth_db).map(|_| ())),
+        ),
+        (
+            "data_root",
+            Check::from_result(check_data_root(base_folder)),
+        ),
+        ("disk_space", check_disk_space(base_folder, min_free_bytes)),
+    ]
+}
+
+fn failed_storage_checks(error: String) -> Vec<(&'static str, Check)> {
+    ["auth_db", "data_root", "disk_space"]
+        .into_iter()
+        .map(|name| (name, Check::from_result(Err(&error))))
+        .collect()
+}
+
+#[get("/healthz")]
+pub async fn healthz() -> HttpResponse {
+    HttpResponse::Ok().json(serde_json::json!({ "ok": true }))
+}
+
+#[get("/readyz")]
+pub async fn readyz(
+    auth_db: web::Data<String>,
+    base_folder: web::Data<PathBuf>,
+    config: web::Data<Config>,
+    shutdown: web::Data<Shutdown>,
+) -> HttpResponse {
+    let mut checks = BTreeMap::new();
+    let min_free_bytes = config.min_free_disk_bytes();
+    let storage = off_worker(move || check_storage(&auth_db, &base_folder, min_free_bytes));
+    let storage = match timeout(CHECK_TIMEOUT, storage).await {
+        Ok(Ok(storage)) => storage,
+        Ok(Err(e)) => failed_storage_checks(e.to_string()),
+        Err(_) => failed_storage_checks(format!(
+            "no answer within {} seconds",
+            CHECK_TIMEOUT.as_secs()
+        )),
+    };
+    checks.extend(storage);
+    checks.insert(
+        "shutdown",
+        Check::from_result(shutdown.reject_new_session()),
+    );
+    let ok = checks.values().all(|c| c.ok);
+    let status = Status { ok, checks };
+    if ok {
+        HttpResponse::Ok().json(status)
+    } else {
+        HttpResponse::ServiceUnavailable().json(status)
+    }
+}
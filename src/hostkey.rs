@@ -0,0 +1,223 @@
+//! host keys handed out to clients on login.
+//!
+//! A host key is a random token handed to the client once and mapped to the
+//! username it was issued for,so password hashes never leave the server and
+//! each login (device) gets a key of its own.Table `hostkeys` of auth db only
+//! keeps the sha256 of each key,a copy of auth db does not let anyone sync.
+//!
+//! Besides the key,the table records the client version,ip and last-seen time
+//! of the device,so that users can see which devices are able to sync and
+//! revoke a lost one.
+use crate::db::add_missing_columns;
+use crate::user::UserError;
+use rand::{rngs::OsRng, RngCore};
+use rusqlite::{Connection, OptionalExtension};
+use sha2::{Digest, Sha256};
+use std::net::IpAddr;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub(crate) const CREATE_HOSTKEYS_TABLE: &str = "CREATE TABLE IF NOT EXISTS hostkeys
+(hkey VARCHAR PRIMARY KEY, username VARCHAR NOT NULL, created INTEGER NOT NULL,
+client_version VARCHAR NOT NULL DEFAULT '', ip VARCHAR NOT NULL DEFAULT '',
+last_seen INTEGER NOT NULL DEFAULT 0, revoked INTEGER NOT NULL DEFAULT 0,
+hashed INTEGER NOT NULL DEFAULT 0)";
+
+/// columns added after table `hostkeys` was first introduced
+const HOSTKEYS_COLUMNS: [(&str, &str); 5] = [
+    ("client_version", "VARCHAR NOT NULL DEFAULT ''"),
+    ("ip", "VARCHAR NOT NULL DEFAULT ''"),
+    ("last_seen", "INTEGER NOT NULL DEFAULT 0"),
+    ("revoked", "INTEGER NOT NULL DEFAULT 0"),
+    // column hkey holds the sha256 of the key rather than the key
+    ("hashed", "INTEGER NOT NULL DEFAULT 0"),
+];
+
+/// length of the key hash prefix used to identify a device
+const DEVICE_ID_LEN: usize = 8;
+
+/// a device (login) holding a host key
+#[derive(Debug, Clone)]
+pub struct Device {
+    /// prefix of the sha256 of the host key
+    pub id: String,
+    pub client_version: String,
+    pub ip: String,
+    pub created: i64,
+    pub last_seen: i64,
+    pub revoked: bool,
+}
+
+/// add missing columns to a table `hostkeys` created by an older version,and
+/// replace the keys it stored in plaintext with their hash.Clients keep their
+/// keys.
+pub(crate) fn upgrade_hostkeys_table(conn: &Connection) -> Result<(), rusqlite::Error> {
+    add_missing_columns(conn, "hostkeys", &HOSTKEYS_COLUMNS)?;
+    let plain = conn
+        .prepare("SELECT hkey FROM hostkeys WHERE hashed=0")?
+        .query_map([], |row| row.get::<_, String>(0))?
+        .collect::<Result<Vec<_>, _>>()?;
+    for hkey in plain {
+        conn.execute(
+            "UPDATE hostkeys SET hkey=?, hashed=1 WHERE hkey=?",
+            [hash_host_key(&hkey), hkey],
+        )?;
+    }
+    Ok(())
+}
+
+pub(crate) fn unix_now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or_default()
+}
+
+/// the sha256 of a host key as stored in auth db,hex-encoded
+fn hash_host_key(hkey: &str) -> String {
+    hex::encode(Sha256::digest(hkey.as_bytes()))
+}
+
+fn create_host_key() -> String {
+    let mut key = [0u8; 32];
+    OsRng.fill_bytes(&mut key);
+    hex::encode(key)
+}
+
+/// create a new host key for the device user logs in from and record its hash
+/// in auth db,the key itself is only ever returned here.
+pub fn issue_host_key<P: AsRef<Path>>(
+    username: &str,
+    client_version: &str,
+    ip: IpAddr,
+    dbpath: P,
+) -> Result<String, UserError> {
+    let hkey = create_host_key();
+    let now = unix_now();
+    let sql =
+        "INSERT INTO hostkeys (hkey, username, created, client_version, ip, last_seen, hashed)
+VALUES (?, ?, ?, ?, ?, ?, 1)";
+    let conn = Connection::open(dbpath)?;
+    conn.execute(
+        sql,
+        rusqlite::params![
+            hash_host_key(&hkey),
+            username,
+            now,
+            client_version,
+            ip.to_string(),
+            now
+        ],
+    )?;
+    conn.close()?;
+    Ok(hkey)
+}
+
+/// return the username a host key was issued for,`None` if the key is unknown,
+/// has been revoked or its user is disabled.
+pub fn username_for_host_key<P: AsRef<Path>>(
+    hkey: &str,
+    dbpath: P,
+) -> Result<Option<String>, UserError> {
+    let sql = "SELECT hostkeys.username FROM hostkeys JOIN auth ON auth.username=hostkeys.username
+WHERE hkey=? AND revoked=0 AND disabled=0";
+    let conn = Connection::open(dbpath)?;
+    let username = conn
+        .query_row(sql, [hash_host_key(hkey)], |row| row.get(0))
+        .optional()?;
+    Ok(username)
+}
+
+/// update client version,ip and last-seen time of the device holding a host key.
+pub fn record_device_activity<P: AsRef<Path>>(
+    hkey: &str,
+    client_version: &str,
+    ip: IpAddr,
+    dbpath: P,
+) -> Result<(), UserError> {
+    // older clients do not send their version on every request,keep the known one
+    let sql = "UPDATE hostkeys SET client_version=coalesce(nullif(?, ''), client_version),
+ip=?, last_seen=? WHERE hkey=?";
+    let conn = Connection::open(dbpath)?;
+    conn.execute(
+        sql,
+        rusqlite::params![
+            client_version,
+            ip.to_string(),
+            unix_now(),
+            hash_host_key(hkey)
+        ],
+    )?;
+    conn.close()?;
+    Ok(())
+}
+
+/// list the devices of a user,most recently seen first.
+pub fn list_devices<P: AsRef<Path>>(username: &str, dbpath: P) -> Result<Vec<Device>, UserError> {
+    let sql = "SELECT hkey, client_version, ip, created, last_seen, revoked FROM hostkeys
+WHERE username=? ORDER BY last_seen DESC";
+    let conn = Connection::open(dbpath)?;
+    let mut stmt = conn.prepare(sql)?;
+    let devices = stmt
+        .query_map([username], |row| {
+            let hkey: String = row.get(0)?;
+            Ok(Device {
+                id: hkey.chars().take(DEVICE_ID_LEN).collect(),
+                client_version: row.get(1)?,
+                ip: row.get(2)?,
+                created: row.get(3)?,
+                last_seen: row.get(4)?,
+                revoked: row.get(5)?,
+            })
+        })?
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(devices)
+}
+
+/// revoke the host key of one device of user,the device is identified by the
+/// id shown in the device list (or any longer prefix of its key hash).
+pub fn revoke_device<P: AsRef<Path>>(
+    username: &str,
+    device: &str,
+    dbpath: P,
+) -> Result<(), UserError> {
+    if device.len() < DEVICE_ID_LEN {
+        return Err(UserError::MissingValues(format!(
+            "device id must be at least {DEVICE_ID_LEN} characters"
+        )));
+    }
+    let conn = Connection::open(dbpath)?;
+    let matched: i64 = conn.query_row(
+        "SELECT count() FROM hostkeys WHERE username=?1 AND substr(hkey, 1, length(?2))=?2",
+        rusqlite::params![username, device],
+        |row| row.get(0),
+    )?;
+    match matched {
+        0 => {
+            return Err(UserError::MissingValues(format!(
+                "no device {device} found for user {username}"
+            )))
+        }
+        1 => {}
+        _ => {
+            return Err(UserError::MissingValues(format!(
+                "device id {device} is ambiguous,use a longer prefix of the key hash"
+            )))
+        }
+    }
+    conn.execute(
+        "UPDATE hostkeys SET revoked=1 WHERE username=?1 AND substr(hkey, 1, length(?2))=?2",
+        rusqlite::params![username, device],
+    )?;
+    conn.close()?;
+    Ok(())
+}
+
+/// invalidate every host key of a user,i.e. after a password change.
+pub fn revoke_host_keys<P: AsRef<Path>>(username: &str, dbpath: P) -> Result<(), UserError> {
+    let sql = "DELETE FROM hostkeys WHERE username=?";
+    let conn = Connection::open(dbpath)?;
+    conn.execute(sql, [username])?;
+    conn.close()?;
+    Ok(())
+}
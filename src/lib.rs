@@ -1,24 +1,43 @@
+pub mod admin;
 pub mod app_config;
+pub mod backup;
+pub mod check;
+pub mod collection;
 pub mod config;
+pub mod dashboard;
 mod db;
 mod error;
+pub mod health;
+pub mod hostkey;
+pub mod lockout;
+pub mod logging;
+pub mod maintenance;
+pub mod media;
+pub mod media_gc;
+pub mod media_store;
+pub mod metrics;
 pub mod parse_args;
+pub mod proxy;
+pub mod quota;
 pub mod response;
 pub mod routes;
+#[cfg(feature = "s3")]
+pub mod s3;
+pub mod server;
+pub mod shutdown;
+pub mod spool;
+pub mod storage;
+pub mod sync_log;
 pub mod user;
-#[cfg(feature = "account")]
 use clap::Parser;
 pub mod request;
-#[cfg(feature = "account")]
 use crate::app_config::run;
 pub use crate::config::Config;
 pub use crate::error::ApplicationError;
-#[cfg(feature = "account")]
 use crate::user::create_auth_db;
-/// It allow account section to exist in config file ,so the feature `account` need be enabled.
+/// run the server with the account section of the config file,added on startup.
 ///
 /// If config argument is absent in arg parsing ,then ./ankisyncd.toml will be used.
-#[cfg(feature = "account")]
 pub async fn server_run_account() -> Result<(), ApplicationError> {
     use std::path::Path;
 
@@ -28,7 +47,7 @@ pub async fn server_run_account() -> Result<(), ApplicationError> {
     // Display config
     if matches.default {
         let default_yaml = Config::default().to_string().expect("Failed to serialize.");
-        println!("{}", default_yaml);
+        println!("{default_yaml}");
         return Ok(());
     }
     // read config file if needed
@@ -45,7 +64,7 @@ pub async fn server_run_account() -> Result<(), ApplicationError> {
     } else {
         let p = Path::new("./ankisyncd.toml");
         if p.exists() {
-            match Config::from_file(p) {
+            match Config::from_file(p).and_then(|c| parse_args::apply_overrides(c, &matches)) {
                 Ok(c) => c,
                 Err(_) => {
                     return Err(ApplicationError::ParseConfig(
@@ -60,19 +79,26 @@ pub async fn server_run_account() -> Result<(), ApplicationError> {
             )));
         }
     };
+    if matches.print_effective_config {
+        println!("{}", conf.to_masked_string()?);
+        return Ok(());
+    }
+    std::env::set_var(
+        "MAX_SYNC_PAYLOAD_MEGS",
+        conf.max_sync_payload_megs().to_string(),
+    );
     // create db if not exist。
     // add to db if account is not empty
     let auth_path = conf.auth_db_path();
     create_auth_db(&auth_path).expect("Failed to create auth database.");
-    #[cfg(feature = "account")]
+    sync_log::create_server_db(conf.server_db_path()).expect("Failed to create server database.");
     if let Some(acnt) = conf.clone().account {
-        create_user_from_conf(acnt, &auth_path);
+        create_user_from_conf(acnt, &auth_path, conf.hashing());
     }
     // Manage account if needed, exit if this is the case
     if let Some(cmd) = matches.cmd.as_ref() {
-        parse_args::manage_user(&cmd, &auth_path);
+        parse_args::manage_user(cmd, &conf);
         return Ok(());
     }
-    run(&conf).await;
-    Ok(())
+    run(&conf).await
 }
@@ -0,0 +1,243 @@
+//! throttling of logins against password guessing.
+//!
+//! Every login is recorded in table `login_attempts` of auth db,once per user
+//! and once per client ip.Within the sliding window set in section
+//! `[security]` a user or ip may only attempt so many logins,and too many
+//! failed ones lock it out.Each further lockout lasts twice as long as the
+//! previous one,up to `max_lockout_secs`.Throttled logins are answered with
+//! 429 and a `Retry-After` header.
+//!
+//! State is kept in auth db so that `ankisyncd user --unlock` can lift a
+//! lockout while the server is running.
+use crate::config::ConfigSecurity;
+use crate::error::ApplicationError;
+use crate::hostkey::unix_now;
+use crate::user::UserError;
+use rusqlite::{Connection, OptionalExtension};
+use std::net::IpAddr;
+use std::path::Path;
+
+pub(crate) const CREATE_LOGIN_ATTEMPTS_TABLE: &str = "CREATE TABLE IF NOT EXISTS login_attempts
+(scope VARCHAR NOT NULL, key VARCHAR NOT NULL, time INTEGER NOT NULL, failed INTEGER NOT NULL)";
+pub(crate) const CREATE_LOCKOUTS_TABLE: &str = "CREATE TABLE IF NOT EXISTS lockouts
+(scope VARCHAR NOT NULL, key VARCHAR NOT NULL, until INTEGER NOT NULL,
+count INTEGER NOT NULL, PRIMARY KEY (scope, key))";
+
+/// what a login is throttled by
+#[derive(Debug, Clone, Copy)]
+enum Scope {
+    User,
+    Ip,
+}
+
+impl Scope {
+    fn as_str(self) -> &'static str {
+        match self {
+            Scope::User => "user",
+            Scope::Ip => "ip",
+        }
+    }
+
+    fn max_attempts(self, config: &ConfigSecurity) -> u32 {
+        match self {
+            Scope::User => config.max_attempts_per_user,
+            Scope::Ip => config.max_attempts_per_ip,
+        }
+    }
+
+    fn max_failures(self, config: &ConfigSecurity) -> u32 {
+        match self {
+            Scope::User => config.max_failures_per_user,
+            Scope::Ip => config.max_failures_per_ip,
+        }
+    }
+}
+
+fn too_many_requests(retry_after: i64) -> ApplicationError {
+    ApplicationError::TooManyRequests {
+        message: "Too many login attempts,please try again later".to_string(),
+        retry_after: retry_after.max(1) as u64,
+    }
+}
+
+/// seconds until scope/key may log in again,`None` if it is not throttled
+fn throttled_for(
+    conn: &Connection,
+    config: &ConfigSecurity,
+    scope: Scope,
+    key: &str,
+    now: i64,
+) -> Result<Option<i64>, rusqlite::Error> {
+    let until: Option<i64> = conn
+        .query_row(
+            "SELECT until FROM lockouts WHERE scope=? AND key=? AND until>?",
+            rusqlite::params![scope.as_str(), key, now],
+            |row| row.get(0),
+        )
+        .optional()?;
+    if let Some(until) = until {
+        return Ok(Some(until - now));
+    }
+    let max_attempts = scope.max_attempts(config);
+    if max_attempts == 0 {
+        return Ok(None);
+    }
+    let (attempts, oldest): (u32, Option<i64>) = conn.query_row(
+        "SELECT count(), min(time) FROM login_attempts WHERE scope=? AND key=? AND time>?",
+        rusqlite::params![scope.as_str(), key, now - config.window_secs as i64],
+        |row| Ok((row.get(0)?, row.get(1)?)),
+    )?;
+    if attempts < max_attempts {
+        return Ok(None);
+    }
+    // a slot frees up once the oldest attempt leaves the window
+    Ok(Some(
+        oldest.unwrap_or(now) + config.window_secs as i64 - now,
+    ))
+}
+
+/// refuse a login of username from ip with 429 if either is throttled.
+pub fn check_login<P: AsRef<Path>>(
+    config: &ConfigSecurity,
+    username: &str,
+    ip: IpAddr,
+    dbpath: P,
+) -> Result<(), ApplicationError> {
+    if !config.enabled {
+        return Ok(());
+    }
+    let check = || -> Result<Option<i64>, rusqlite::Error> {
+        let conn = Connection::open(&dbpath)?;
+        let now = unix_now();
+        conn.execute(
+            "DELETE FROM login_attempts WHERE time<=?",
+            [now - config.window_secs as i64],
+        )?;
+        let by_user = throttled_for(&conn, config, Scope::User, username, now)?;
+        let by_ip = throttled_for(&conn, config, Scope::Ip, &ip.to_string(), now)?;
+        Ok(by_user.max(by_ip))
+    };
+    match check() {
+        Ok(None) => Ok(()),
+        Ok(Some(retry_after)) => {
+            log::warn!("login of user {username} from {ip} throttled for {retry_after}s");
+            Err(too_many_requests(retry_after))
+        }
+        Err(e) => Err(ApplicationError::InternalServerError(e.to_string())),
+    }
+}
+
+/// lock scope/key out if it failed too often within the window
+fn lock_if_needed(
+    conn: &Connection,
+    config: &ConfigSecurity,
+    scope: Scope,
+    key: &str,
+    now: i64,
+) -> Result<(), rusqlite::Error> {
+    let max_failures = scope.max_failures(config);
+    if max_failures == 0 {
+        return Ok(());
+    }
+    let failures: u32 = conn.query_row(
+        "SELECT count() FROM login_attempts WHERE scope=? AND key=? AND failed=1 AND time>?",
+        rusqlite::params![scope.as_str(), key, now - config.window_secs as i64],
+        |row| row.get(0),
+    )?;
+    if failures < max_failures {
+        return Ok(());
+    }
+    let previous: Option<(i64, u32)> = conn
+        .query_row(
+            "SELECT until, count FROM lockouts WHERE scope=? AND key=?",
+            rusqlite::params![scope.as_str(), key],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )
+        .optional()?;
+    // the backoff starts over once the last lockout is long past
+    let count = match previous {
+        Some((until, count)) if until + (config.max_lockout_secs as i64) > now => count + 1,
+        _ => 1,
+    };
+    let duration = config
+        .lockout_secs
+        .saturating_mul(1u64 << (count - 1).min(32))
+        .min(config.max_lockout_secs);
+    conn.execute(
+        "INSERT OR REPLACE INTO lockouts (scope, key, until, count) VALUES (?, ?, ?, ?)",
+        rusqlite::params![scope.as_str(), key, now + duration as i64, count],
+    )?;
+    // the next lockout takes as many new failures
+    conn.execute(
+        "DELETE FROM login_attempts WHERE scope=? AND key=? AND failed=1",
+        rusqlite::params![scope.as_str(), key],
+    )?;
+    log::warn!(
+        "{} {key} locked out for {duration}s after {failures} failed logins",
+        scope.as_str()
+    );
+    Ok(())
+}
+
+/// record the outcome of a login of username from ip.
+///
+/// failures may lock user or ip out,a success clears the failures of user.
+pub fn record_login<P: AsRef<Path>>(
+    config: &ConfigSecurity,
+    username: &str,
+    ip: IpAddr,
+    success: bool,
+    dbpath: P,
+) -> Result<(), rusqlite::Error> {
+    if !config.enabled {
+        return Ok(());
+    }
+    let conn = Connection::open(dbpath)?;
+    let now = unix_now();
+    let ip = ip.to_string();
+    for (scope, key) in [(Scope::User, username), (Scope::Ip, ip.as_str())] {
+        conn.execute(
+            "INSERT INTO login_attempts (scope, key, time, failed) VALUES (?, ?, ?, ?)",
+            rusqlite::params![scope.as_str(), key, now, !success],
+        )?;
+        if !success {
+            lock_if_needed(&conn, config, scope, key, now)?;
+        }
+    }
+    if success {
+        conn.execute(
+            "DELETE FROM login_attempts WHERE scope='user' AND key=? AND failed=1",
+            [username],
+        )?;
+        conn.execute(
+            "DELETE FROM lockouts WHERE scope='user' AND key=?",
+            [username],
+        )?;
+    }
+    conn.close().map_err(|(_, e)| e)?;
+    Ok(())
+}
+
+/// lift the lockout of a username or ip address and forget its failed logins,
+/// return whether it was locked out.
+pub fn unlock<P: AsRef<Path>>(username_or_ip: &str, dbpath: P) -> Result<bool, UserError> {
+    let scope = match username_or_ip.parse::<IpAddr>() {
+        Ok(_) => Scope::Ip,
+        Err(_) => Scope::User,
+    };
+    let conn = Connection::open(dbpath)?;
+    let locked = conn.execute(
+        "DELETE FROM lockouts WHERE scope=? AND key=? AND until>?",
+        rusqlite::params![scope.as_str(), username_or_ip, unix_now()],
+    )? > 0;
+    conn.execute(
+        "DELETE FROM lockouts WHERE scope=? AND key=?",
+        rusqlite::params![scope.as_str(), username_or_ip],
+    )?;
+    conn.execute(
+        "DELETE FROM login_attempts WHERE scope=? AND key=?",
+        rusqlite::params![scope.as_str(), username_or_ip],
+    )?;
+    conn.close()?;
+    Ok(locked)
+}
@@ -0,0 +1,218 @@
+//! logging,as text or as one JSON object per line.
+//!
+//! Each http request is handled inside a `RequestContext` carrying a request
+//! id (taken from header `X-Request-Id` if the client sent a usable one) and,as
+//! soon as they are known,the user,sync method and sync version.Every line
+//! logged while handling the request includes them,also from sync operations
+//! running on the blocking thread pool.The request id is sent back in the
+//! response header `X-Request-Id`.
+use crate::config::ConfigLogging;
+use crate::proxy;
+use actix_web::{
+    dev::{self, Service, ServiceRequest, ServiceResponse, Transform},
+    http::header::{HeaderName, HeaderValue},
+    middleware::Logger,
+    Error,
+};
+use futures_util::future::LocalBoxFuture;
+use std::future::{ready, Ready};
+use std::io::Write;
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+
+/// longest request id accepted from a client
+const MAX_REQUEST_ID_LEN: usize = 128;
+
+tokio::task_local! {
+    static CONTEXT: Arc<RequestContext>;
+}
+
+#[derive(Default, Clone)]
+struct ContextFields {
+    username: Option<String>,
+    method: Option<String>,
+    sync_version: Option<u8>,
+}
+
+/// the request a log line belongs to
+pub struct RequestContext {
+    request_id: String,
+    fields: Mutex<ContextFields>,
+}
+
+impl RequestContext {
+    fn new(request_id: String) -> Self {
+        RequestContext {
+            request_id,
+            fields: Mutex::new(ContextFields::default()),
+        }
+    }
+
+    fn fields(&self) -> ContextFields {
+        self.fields.lock().map(|f| f.clone()).unwrap_or_default()
+    }
+}
+
+/// the context of the request being handled,if any
+pub fn current() -> Option<Arc<RequestContext>> {
+    CONTEXT.try_with(|c| c.clone()).ok()
+}
+
+fn update(f: impl FnOnce(&mut ContextFields)) {
+    let _ = CONTEXT.try_with(|c| {
+        if let Ok(mut fields) = c.fields.lock() {
+            f(&mut fields);
+        }
+    });
+}
+
+/// attach the user a request was authenticated for to its log lines
+pub fn set_username(username: &str) {
+    update(|f| f.username = Some(username.to_string()));
+}
+
+/// attach sync method and version of a request to its log lines
+pub fn set_sync_method(method: &str, sync_version: u8) {
+    update(|f| {
+        f.method = Some(method.to_string());
+        f.sync_version = Some(sync_version);
+    });
+}
+
+/// run f inside context,used to carry the context of a request over to the
+/// blocking thread pool.
+pub fn with_context<R>(context: Option<Arc<RequestContext>>, f: impl FnOnce() -> R) -> R {
+    match context {
+        Some(c) => CONTEXT.sync_scope(c, f),
+        None => f(),
+    }
+}
+
+/// the request id sent by the client if it is one we can log and echo back
+fn client_request_id(req: &ServiceRequest) -> Option<String> {
+    let id = req.headers().get(REQUEST_ID_HEADER)?.to_str().ok()?;
+    let usable = !id.is_empty()
+        && id.len() <= MAX_REQUEST_ID_LEN
+        && id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-_.:".contains(c));
+    usable.then(|| id.to_string())
+}
+
+fn new_request_id() -> String {
+    hex::encode(rand::random::<[u8; 16]>())
+}
+
+#[doc(hidden)]
+pub struct RequestContextService<S> {
+    service: Rc<S>,
+}
+
+impl<S, B> Service<ServiceRequest> for RequestContextService<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    dev::forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let service = self.service.clone();
+        let request_id = client_request_id(&req).unwrap_or_else(new_request_id);
+        let context = Arc::new(RequestContext::new(request_id.clone()));
+        Box::pin(CONTEXT.scope(context, async move {
+            let mut res = service.call(req).await?;
+            if let Ok(value) = HeaderValue::from_str(&request_id) {
+                res.headers_mut()
+                    .insert(HeaderName::from_static(REQUEST_ID_HEADER), value);
+            }
+            Ok(res)
+        }))
+    }
+}
+
+/// middleware running each request inside a `RequestContext`
+#[derive(Clone, Debug)]
+pub struct RequestContextMiddleware;
+
+impl<S: 'static, B> Transform<S, ServiceRequest> for RequestContextMiddleware
+where
+    S::Future: 'static,
+    B: 'static,
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+    type Transform = RequestContextService<S>;
+    type InitError = ();
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(RequestContextService {
+            service: Rc::new(service),
+        }))
+    }
+}
+
+/// access log format of `middleware::Logger`,the default one with the client
+/// ip behind trusted proxies and the request id
+const ACCESS_LOG_FORMAT: &str =
+    r#"%{client_ip}xi "%r" %s %b "%{Referer}i" "%{User-Agent}i" %T request_id=%{x-request-id}o"#;
+
+/// the access log middleware
+pub fn access_log() -> Logger {
+    Logger::new(ACCESS_LOG_FORMAT).custom_request_replace("client_ip", |req| {
+        proxy::client_ip(req.request())
+            .map(|ip| ip.to_string())
+            .unwrap_or_else(|| "-".to_string())
+    })
+}
+
+/// set up the global logger,the level is read from `RUST_LOG` (default info).
+pub fn init(config: &ConfigLogging) {
+    let env = env_logger_successor::Env::new().default_filter_or("info");
+    let mut builder = env_logger_successor::Builder::from_env(env);
+    let json = config.format.eq_ignore_ascii_case("json");
+    builder.format(move |buf, record| {
+        let now = chrono::Local::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, false);
+        let context = current();
+        let fields = context.as_ref().map(|c| c.fields()).unwrap_or_default();
+        let request_id = context.as_ref().map(|c| c.request_id.as_str());
+        if json {
+            let line = serde_json::json!({
+                "time": now,
+                "level": record.level().as_str(),
+                "target": record.target(),
+                "message": record.args().to_string(),
+                "request_id": request_id,
+                "user": fields.username,
+                "method": fields.method,
+                "sync_version": fields.sync_version,
+            });
+            return writeln!(buf, "{line}");
+        }
+        write!(buf, "[{now} {:<5} {}]", record.level(), record.target())?;
+        if let Some(id) = request_id {
+            write!(buf, " request_id={id}")?;
+        }
+        if let Some(user) = &fields.username {
+            write!(buf, " user={user}")?;
+        }
+        if let Some(method) = &fields.method {
+            write!(buf, " method={method}")?;
+        }
+        if let Some(version) = fields.sync_version {
+            write!(buf, " sync_version={version}")?;
+        }
+        writeln!(buf, " {}", record.args())
+    });
+    if let Err(e) = builder.try_init() {
+        eprintln!("failed to set up logging: {e}");
+    }
+}
@@ -1,29 +1,43 @@
+pub mod admin;
 pub mod app_config;
+pub mod backup;
+pub mod check;
+pub mod collection;
 pub mod config;
+pub mod dashboard;
 mod db;
 mod error;
+pub mod health;
+pub mod hostkey;
+pub mod lockout;
+pub mod logging;
+pub mod maintenance;
+pub mod media;
+pub mod media_gc;
+pub mod media_store;
+pub mod metrics;
 pub mod parse_args;
+pub mod proxy;
+pub mod quota;
 pub mod request;
 pub mod response;
 pub mod routes;
+#[cfg(feature = "s3")]
+pub mod s3;
+pub mod server;
+pub mod shutdown;
+pub mod spool;
+pub mod storage;
+pub mod sync_log;
 pub mod user;
 #[cfg(feature = "tls")]
 use self::app_config::{load_ssl, run_tls};
 use self::{config::Config, user::create_auth_db};
 
-use crate::user::{add_user, user_exists};
+use crate::user::create_user_from_conf;
 use clap::Parser;
-use lazy_static::lazy_static;
 use std::env;
 
-lazy_static! {
-    // when set,it will be used in method decode_zstd_body_for_server while paring request body.
-    static ref MAX_COLLECTION_UPLOAD_SIZE: String =
-        env::var("MAX_SYNC_PAYLOAD_MEGS").unwrap_or_else(|_| "1000".to_string());
-    static ref USERNAME: String = env::var("ANKISYNCD_USERNAME").unwrap_or_else(|_| "".to_string());
-    static ref PASSWORD: String = env::var("ANKISYNCD_PASSWORD").unwrap_or_else(|_| "".to_string());
-}
-
 #[actix_web::main]
 async fn main() -> Result<(), ()> {
     let matches = parse_args::Arg::parse();
@@ -41,20 +55,30 @@ async fn main() -> Result<(), ()> {
             return Err(());
         }
     };
+    if matches.print_effective_config {
+        match conf.to_masked_string() {
+            Ok(s) => println!("{s}"),
+            Err(e) => eprintln!("Error while printing configuration: {e}"),
+        }
+        return Ok(());
+    }
+    // anki reads the limit of a sync request from this env var while parsing request bodies
+    env::set_var(
+        "MAX_SYNC_PAYLOAD_MEGS",
+        conf.max_sync_payload_megs().to_string(),
+    );
     // create db if not exist
     let auth_path = conf.auth_db_path();
     create_auth_db(&auth_path).expect("Failed to create auth database.");
+    sync_log::create_server_db(conf.server_db_path()).expect("Failed to create server database.");
 
-    // Manage account if needed, exit if this is the case
-    if !USERNAME.is_empty()
-        && !PASSWORD.is_empty()
-        && !user_exists(&USERNAME, &auth_path).expect("user existing error")
-    {
-        add_user(&[USERNAME.to_string(), PASSWORD.to_string()], &auth_path)
-            .expect("adding user from env vars fail");
+    // add the account of config file or env vars if it does not exist
+    if let Some(acnt) = conf.account.clone() {
+        create_user_from_conf(acnt, &auth_path, conf.hashing());
     }
+    // Manage account if needed, exit if this is the case
     if let Some(cmd) = matches.cmd.as_ref() {
-        parse_args::manage_user(cmd, &auth_path);
+        parse_args::manage_user(cmd, &conf);
         return Ok(());
     }
     #[cfg(feature = "tls")]
@@ -67,18 +91,18 @@ async fn main() -> Result<(), ()> {
                     return Err(());
                 }
             };
-            run_tls(&conf, tls_conf).await.unwrap();
+            if let Err(e) = run_tls(&conf, tls_conf).await {
+                eprintln!("Error while running the server: {e}");
+                return Err(());
+            }
             return Ok(());
         }
     } else if conf.encryption_enabled() {
         eprintln!("TLS encryption is enabled but will be ignored as encryption support was not built in the binary.");
     }
-    //  set env var max collection upload size
-    env::set_var(
-        "MAX_SYNC_PAYLOAD_MEGS",
-        MAX_COLLECTION_UPLOAD_SIZE.to_string(),
-    );
-
-    app_config::run(&conf).await.unwrap();
+    if let Err(e) = app_config::run(&conf).await {
+        eprintln!("Error while running the server: {e}");
+        return Err(());
+    }
     Ok(())
 }
@@ -0,0 +1,145 @@
+//! maintenance state of users,shared with the running server through marker
+//! files in the user folder,so that a command run from another process can
+//! take a user out of service.
+//!
+//! While `.maintenance` exists every sync of the user is rejected with 503,and
+//! the server closes the collection of the user within a poll interval,anki
+//! holding it open with an exclusive lock.Commands working on the collection
+//! wait for the lock to be released before opening it.`.restored` tells the
+//! server that the collection was replaced on disk and must be reopened before
+//! the next sync.
+use crate::collection::collection_path;
+use crate::error::ApplicationError;
+use crate::hostkey::unix_now;
+use crate::server::{close_user_collection, SyncServer};
+use rusqlite::{Connection, ErrorCode};
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+const MAINTENANCE_MARKER: &str = ".maintenance";
+const RESTORED_MARKER: &str = ".restored";
+
+/// how long a command waits for the server to close a collection,a few poll
+/// intervals of the server
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// keeps a user out of service until dropped
+pub struct MaintenanceGuard {
+    marker: PathBuf,
+}
+
+impl MaintenanceGuard {
+    pub fn enter(user_folder: &Path) -> std::io::Result<Self> {
+        fs::create_dir_all(user_folder)?;
+        let marker = user_folder.join(MAINTENANCE_MARKER);
+        fs::write(&marker, unix_now().to_string())?;
+        Ok(MaintenanceGuard { marker })
+    }
+
+    /// take the user out of service and wait until no process holds its
+    /// collection open,so that it can be opened from here.
+    pub fn lock_out(user_folder: &Path) -> Result<Self, ApplicationError> {
+        let guard = Self::enter(user_folder)?;
+        wait_until_closed(&collection_path(user_folder), CLOSE_TIMEOUT)?;
+        Ok(guard)
+    }
+}
+
+/// whether another connection holds a lock on the database at path
+fn is_locked(path: &Path) -> Result<bool, rusqlite::Error> {
+    let conn = Connection::open(path)?;
+    conn.busy_timeout(Duration::ZERO)?;
+    match conn.execute_batch("BEGIN EXCLUSIVE; ROLLBACK;") {
+        Ok(()) => Ok(false),
+        Err(rusqlite::Error::SqliteFailure(e, _))
+            if matches!(e.code, ErrorCode::DatabaseBusy | ErrorCode::DatabaseLocked) =>
+        {
+            Ok(true)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// wait for the collection at path to be closed by the server
+fn wait_until_closed(path: &Path, timeout: Duration) -> Result<(), ApplicationError> {
+    if !path.exists() {
+        return Ok(());
+    }
+    let started = Instant::now();
+    while is_locked(path)? {
+        if started.elapsed() > timeout {
+            return Err(ApplicationError::ServiceUnavailable(format!(
+                "{} is still open in another process,stop the server (or a server not closing collections under maintenance) and try again",
+                path.display()
+            )));
+        }
+        std::thread::sleep(Duration::from_millis(500));
+    }
+    Ok(())
+}
+
+/// close the collections of the loaded users under maintenance,so that the
+/// command that took them out of service can open them.
+pub fn close_under_maintenance(server: &SyncServer, base_folder: &Path) {
+    for username in server.usernames() {
+        if !base_folder
+            .join(&username)
+            .join(MAINTENANCE_MARKER)
+            .exists()
+        {
+            continue;
+        }
+        let closed = server.with_user(&username, |user| {
+            let open = user.col.is_some();
+            close_user_collection(user);
+            open
+        });
+        if closed == Some(true) {
+            log::info!("user {username} is under maintenance,closed its collection");
+        }
+    }
+}
+
+impl Drop for MaintenanceGuard {
+    fn drop(&mut self) {
+        if let Err(e) = fs::remove_file(&self.marker) {
+            log::error!(
+                "failed to remove maintenance marker {}: {e}",
+                self.marker.display()
+            );
+        }
+    }
+}
+
+/// tell the server to reopen the collection in user_folder before its next sync.
+pub fn mark_restored(user_folder: &Path) -> std::io::Result<()> {
+    fs::write(user_folder.join(RESTORED_MARKER), unix_now().to_string())
+}
+
+/// reject syncs of a user under maintenance,and close the collection the server
+/// holds open if it is being or has been replaced on disk.
+pub fn check_maintenance(
+    server: &SyncServer,
+    user_folder: &Path,
+    username: &str,
+) -> Result<(), ApplicationError> {
+    let maintenance = user_folder.join(MAINTENANCE_MARKER).exists();
+    let restored = user_folder.join(RESTORED_MARKER);
+    if !maintenance && !restored.exists() {
+        return Ok(());
+    }
+    server.with_user(username, close_user_collection);
+    if maintenance {
+        return Err(ApplicationError::ServiceUnavailable(format!(
+            "Account {username} is under maintenance,please try again later"
+        )));
+    }
+    match fs::remove_file(&restored) {
+        Err(e) if e.kind() != ErrorKind::NotFound => return Err(e.into()),
+        _ => {}
+    }
+    log::info!("collection of user {username} was restored,reopening it");
+    Ok(())
+}
@@ -0,0 +1,152 @@
+//! read-only access to the media database `ServerMediaManager` keeps in each
+//! user folder,and to the zip files clients send with `uploadChanges`.
+use crate::error::ApplicationError;
+use rusqlite::{Connection, OpenFlags, OptionalExtension};
+use std::fs::File;
+use std::io::{self, Cursor, Read, Seek, Write};
+use std::path::{Path, PathBuf};
+use zip::write::FileOptions;
+use zip::{CompressionMethod, ZipArchive, ZipWriter};
+
+/// file name of the media database inside a user folder
+pub const MEDIA_DB_FILE: &str = "media.db";
+/// folder holding the media files inside a user folder
+pub const MEDIA_FOLDER: &str = "media";
+
+pub fn media_db_path(user_folder: &Path) -> PathBuf {
+    user_folder.join(MEDIA_DB_FILE)
+}
+
+pub fn media_folder(user_folder: &Path) -> PathBuf {
+    user_folder.join(MEDIA_FOLDER)
+}
+
+/// number and total size in bytes of the media files of a user,deleted files
+/// are kept in the database with an empty checksum and are not counted.
+pub fn media_usage(user_folder: &Path) -> Result<(u64, u64), rusqlite::Error> {
+    let path = media_db_path(user_folder);
+    if !path.exists() {
+        return Ok((0, 0));
+    }
+    let conn = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+    conn.query_row(
+        "SELECT count(), coalesce(sum(size), 0) FROM media WHERE csum IS NOT NULL",
+        [],
+        |row| Ok((row.get::<_, i64>(0)? as u64, row.get::<_, i64>(1)? as u64)),
+    )
+}
+
+/// name of the zip entry listing the changes of an `uploadChanges` request
+const ZIP_META: &str = "_meta";
+
+fn changes_of<R: Read + Seek>(
+    zip: &mut ZipArchive<R>,
+) -> Result<Vec<(String, Option<String>)>, ApplicationError> {
+    let mut meta = vec![];
+    zip.by_name(ZIP_META)?.read_to_end(&mut meta)?;
+    Ok(serde_json::from_slice(&meta)?)
+}
+
+/// changes carried by the `uploadChanges` zip at path:(file name,zip entry
+/// name),the entry name is absent for deletions.
+pub fn upload_changes(path: &Path) -> Result<Vec<(String, Option<String>)>, ApplicationError> {
+    changes_of(&mut ZipArchive::new(File::open(path)?)?)
+}
+
+/// the `uploadChanges` zip at path split into zips of a single change each,in
+/// order,so that anki only ever holds one file of it in memory.A zip without
+/// changes is passed on as it is.
+pub struct SplitUpload {
+    path: PathBuf,
+    zip: ZipArchive<File>,
+    changes: std::vec::IntoIter<(String, Option<String>)>,
+    empty: bool,
+}
+
+impl SplitUpload {
+    pub fn open(path: &Path) -> Result<Self, ApplicationError> {
+        let mut zip = ZipArchive::new(File::open(path)?)?;
+        let changes = changes_of(&mut zip)?;
+        Ok(SplitUpload {
+            path: path.to_owned(),
+            zip,
+            empty: changes.is_empty(),
+            changes: changes.into_iter(),
+        })
+    }
+
+    fn single(
+        &mut self,
+        fname: String,
+        entry: Option<String>,
+    ) -> Result<Vec<u8>, ApplicationError> {
+        let options = FileOptions::default().compression_method(CompressionMethod::Stored);
+        let mut single = ZipWriter::new(Cursor::new(vec![]));
+        let meta = match entry {
+            Some(entry) => {
+                single.start_file("0", options)?;
+                io::copy(&mut self.zip.by_name(&entry)?, &mut single)?;
+                serde_json::json!([[fname, "0"]])
+            }
+            None => serde_json::json!([[fname, null]]),
+        };
+        single.start_file(ZIP_META, options)?;
+        single.write_all(meta.to_string().as_bytes())?;
+        Ok(single.finish()?.into_inner())
+    }
+}
+
+impl Iterator for SplitUpload {
+    type Item = Result<Vec<u8>, ApplicationError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.empty {
+            self.empty = false;
+            return Some(std::fs::read(&self.path).map_err(ApplicationError::from));
+        }
+        let (fname, entry) = self.changes.next()?;
+        Some(self.single(fname, entry))
+    }
+}
+
+/// number and total size of the media files of a user once the changes of the
+/// `uploadChanges` zip at path have been applied.
+pub fn usage_after_upload(
+    user_folder: &Path,
+    zip_path: &Path,
+) -> Result<(u64, u64), ApplicationError> {
+    let (mut files, mut bytes) = media_usage(user_folder)?;
+    let mut zip = ZipArchive::new(File::open(zip_path)?)?;
+    let changes = changes_of(&mut zip)?;
+    let path = media_db_path(user_folder);
+    let conn = if path.exists() {
+        Some(Connection::open_with_flags(
+            path,
+            OpenFlags::SQLITE_OPEN_READ_ONLY,
+        )?)
+    } else {
+        None
+    };
+    for (fname, entry) in changes {
+        // a changed file replaces the existing one
+        let existing: Option<i64> = match &conn {
+            Some(conn) => conn
+                .query_row(
+                    "SELECT size FROM media WHERE fname=? AND csum IS NOT NULL",
+                    [&fname],
+                    |row| row.get(0),
+                )
+                .optional()?,
+            None => None,
+        };
+        if let Some(size) = existing {
+            files = files.saturating_sub(1);
+            bytes = bytes.saturating_sub(size as u64);
+        }
+        if let Some(entry) = entry {
+            files += 1;
+            bytes += zip.by_name(&entry)?.size();
+        }
+    }
+    Ok((files, bytes))
+}
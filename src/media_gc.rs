@@ -0,0 +1,260 @@
+//! reconcile the media files kept for users with their media databases.
+//!
+//! The media database `ServerMediaManager` keeps is what clients are told the
+//! server has,files the database does not list (i.e. left behind by a crash)
+//! only take up space,while files it lists that are missing or whose content
+//! does not match the recorded checksum break the media sync of every client
+//! asking for them.`media gc` reports all three,and unless run with
+//! `--dry-run` deletes unlisted files and drops the entries of missing and
+//! corrupt files (deleting the latter) from the media database.Clients holding
+//! such a file keep their copy.
+//!
+//! Files in a storage other than the media folders are checked against the
+//! checksum the storage keeps along with them,files it keeps none for are
+//! only downloaded and checked with `--verify-content`.
+use crate::config::Config;
+use crate::error::ApplicationError;
+use crate::maintenance::MaintenanceGuard;
+use crate::media::{media_db_path, media_folder};
+use crate::media_store::{file_sha1, media_checksums, MediaStore};
+use crate::storage::{self, Area, Storage};
+use crate::user::{user_exists, user_list};
+use rusqlite::Connection;
+use sha1::{Digest, Sha1};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+/// outcome of reconciling the media of a user
+#[derive(Debug, Default)]
+pub struct MediaReport {
+    /// files listed in the media database
+    pub files: usize,
+    pub bytes: u64,
+    /// files in storage the media database does not list,as (name,size)
+    pub unreferenced: Vec<(String, u64)>,
+    /// files listed in the media database that are not in storage
+    pub missing: Vec<String>,
+    /// files whose content does not match the checksum of the media database
+    pub mismatched: Vec<String>,
+    /// files whose content was not checked,as they would have to be fetched
+    pub unchecked: usize,
+}
+
+impl MediaReport {
+    fn unreferenced_bytes(&self) -> u64 {
+        self.unreferenced.iter().map(|(_, size)| size).sum()
+    }
+
+    fn is_clean(&self) -> bool {
+        self.unreferenced.is_empty() && self.missing.is_empty() && self.mismatched.is_empty()
+    }
+}
+
+/// what is known of the content of a stored media file
+enum Content {
+    /// sha1,hex-encoded
+    Sha1(String),
+    Missing,
+    Unchecked,
+}
+
+/// sha1 of a stored media file,from the checksum the storage keeps if it is
+/// not the media folder.The file is only fetched to hash it if the storage
+/// keeps none and verify_content is set.
+fn stored_sha1(
+    storage: &dyn Storage,
+    username: &str,
+    user_folder: &Path,
+    fname: &str,
+    verify_content: bool,
+) -> Result<Content, ApplicationError> {
+    if storage.is_working_copy(Area::Media) {
+        return Ok(Content::Sha1(file_sha1(
+            &media_folder(user_folder).join(fname),
+        )?));
+    }
+    if let Some(sha1) = storage.recorded_sha1(Area::Media, username, fname)? {
+        return Ok(Content::Sha1(sha1));
+    }
+    if !verify_content {
+        return Ok(Content::Unchecked);
+    }
+    Ok(match storage.get(Area::Media, username, fname)? {
+        Some(data) => Content::Sha1(hex::encode(Sha1::digest(data))),
+        None => Content::Missing,
+    })
+}
+
+/// compare the media files of a user in storage with the media database
+pub fn inspect_media(
+    storage: &dyn Storage,
+    username: &str,
+    user_folder: &Path,
+    verify_content: bool,
+) -> Result<MediaReport, ApplicationError> {
+    let mut listed: HashMap<String, String> = media_checksums(user_folder)?.into_iter().collect();
+    let mut report = MediaReport {
+        files: listed.len(),
+        ..Default::default()
+    };
+    let mut objects = storage.list(Area::Media, username)?;
+    objects.sort_by(|a, b| a.name.cmp(&b.name));
+    for object in objects {
+        let sha1 = match listed.remove(&object.name) {
+            Some(sha1) => sha1,
+            None => {
+                report.unreferenced.push((object.name, object.size));
+                continue;
+            }
+        };
+        report.bytes += object.size;
+        match stored_sha1(storage, username, user_folder, &object.name, verify_content)? {
+            Content::Sha1(actual) if actual == sha1 => {}
+            Content::Sha1(_) => report.mismatched.push(object.name),
+            Content::Missing => report.missing.push(object.name),
+            Content::Unchecked => report.unchecked += 1,
+        }
+    }
+    report.missing.extend(listed.into_keys());
+    report.missing.sort();
+    Ok(report)
+}
+
+/// drop the entries of files from the media database and recount its totals
+fn drop_entries(user_folder: &Path, fnames: &[&String]) -> Result<(), rusqlite::Error> {
+    let mut conn = Connection::open(media_db_path(user_folder))?;
+    let tx = conn.transaction()?;
+    for fname in fnames {
+        tx.execute("DELETE FROM media WHERE fname=?", [fname])?;
+    }
+    tx.execute(
+        "UPDATE meta SET total_bytes=(SELECT coalesce(sum(size), 0) FROM media WHERE csum IS NOT NULL),
+        total_nonempty_files=(SELECT count() FROM media WHERE csum IS NOT NULL)",
+        [],
+    )?;
+    tx.commit()
+}
+
+/// delete unreferenced and corrupt files and drop the entries of missing and
+/// corrupt ones,as found by `inspect_media`.
+pub fn repair_media(
+    storage: &dyn Storage,
+    store: Option<&MediaStore>,
+    username: &str,
+    user_folder: &Path,
+    report: &MediaReport,
+) -> Result<(), ApplicationError> {
+    let conn = store.map(|s| s.open()).transpose()?;
+    let deleted = report
+        .unreferenced
+        .iter()
+        .map(|(fname, _)| fname)
+        .chain(&report.mismatched);
+    for fname in deleted {
+        storage.delete(Area::Media, username, fname)?;
+        if let (Some(store), Some(conn)) = (store, &conn) {
+            store.release(conn, username, fname)?;
+        }
+    }
+    let dropped: Vec<_> = report.missing.iter().chain(&report.mismatched).collect();
+    if !dropped.is_empty() {
+        drop_entries(user_folder, &dropped)?;
+        if let (Some(store), Some(conn)) = (store, &conn) {
+            for fname in &report.missing {
+                store.release(conn, username, fname)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// handle `media gc`,for the given users or all of them
+pub fn gc_media(
+    config: &Config,
+    usernames: &[String],
+    all: bool,
+    dry_run: bool,
+    verify_content: bool,
+) -> Result<(), ApplicationError> {
+    let users = user_list(config.auth_db_path())?.unwrap_or_default();
+    let usernames = match all {
+        true => users.clone(),
+        false => usernames.to_vec(),
+    };
+    for username in &usernames {
+        if !user_exists(username, config.auth_db_path())? {
+            return Err(ApplicationError::ValueNotFound(format!(
+                "no such user {username}"
+            )));
+        }
+    }
+    let storage = storage::from_config(config)?;
+    let storage = storage.as_ref();
+    let store = MediaStore::from_config(config);
+    let data_root = PathBuf::from(config.data_root_path());
+    let mut total = MediaReport::default();
+    for username in &usernames {
+        let user_folder = data_root.join(username);
+        // keep syncs from changing the media while it is compared and repaired
+        let guard = match dry_run {
+            true => None,
+            false => Some(MaintenanceGuard::enter(&user_folder)?),
+        };
+        let report = inspect_media(storage, username, &user_folder, verify_content)?;
+        println!(
+            "{username}: {} files,{} bytes,{} unreferenced ({} bytes),{} missing,{} not matching their checksum",
+            report.files,
+            report.bytes,
+            report.unreferenced.len(),
+            report.unreferenced_bytes(),
+            report.missing.len(),
+            report.mismatched.len()
+        );
+        for (fname, size) in &report.unreferenced {
+            println!("\tunreferenced\t{fname}\t{size} bytes");
+        }
+        for fname in &report.missing {
+            println!("\tmissing\t{fname}");
+        }
+        for fname in &report.mismatched {
+            println!("\tmismatch\t{fname}");
+        }
+        if !dry_run && !report.is_clean() {
+            repair_media(storage, store.as_ref(), username, &user_folder, &report)?;
+        }
+        drop(guard);
+        total.files += report.files;
+        total.bytes += report.bytes;
+        total.unreferenced.extend(report.unreferenced);
+        total.missing.extend(report.missing);
+        total.mismatched.extend(report.mismatched);
+        total.unchecked += report.unchecked;
+    }
+    println!(
+        "total: {} files,{} bytes,{} unreferenced ({} bytes),{} missing,{} not matching their checksum",
+        total.files,
+        total.bytes,
+        total.unreferenced.len(),
+        total.unreferenced_bytes(),
+        total.missing.len(),
+        total.mismatched.len()
+    );
+    if let Some(store) = store.as_ref().filter(|_| all) {
+        let (refs, files, bytes) = store.collect_orphans(&users, dry_run)?;
+        println!(
+            "media store: {refs} references of deleted users,{files} stored files nothing refers to ({bytes} bytes)"
+        );
+    }
+    if total.unchecked > 0 {
+        println!(
+            "the content of {} files without a checksum in storage was not checked,run with --verify-content to download and check them",
+            total.unchecked
+        );
+    }
+    match dry_run {
+        true => println!("dry run,nothing was changed"),
+        false if !total.is_clean() => println!("repaired"),
+        false => {}
+    }
+    Ok(())
+}
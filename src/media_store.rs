@@ -0,0 +1,414 @@
+//! content-addressed store of media files shared by all users.
+//!
+//! With `dedup_media` set in section `[storage]`,a media file is kept once as
+//! `<root>/media-store/<ab>/<sha1>`,keyed by the checksum the media database
+//! records for it,and the media folder of each user holding it has a hard
+//! link to it.Table `media_refs` of server db records which file of which user
+//! refers to each stored file,a stored file is deleted along with its last
+//! reference.
+//!
+//! anki writes into the media folders as usual.Files about to be replaced by
+//! an `uploadChanges` get a private copy beforehand so that anki never writes
+//! into a shared file,and the files it added are linked to the store
+//! afterwards.The store must be on the file system of the data root.
+use crate::config::Config;
+use crate::error::ApplicationError;
+use crate::media::{media_db_path, media_folder};
+use rusqlite::types::ValueRef;
+use rusqlite::{Connection, OpenFlags, OptionalExtension, Transaction, TransactionBehavior};
+use sha1::{Digest, Sha1};
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+
+pub const CREATE_MEDIA_REFS_TABLE: &str = "CREATE TABLE IF NOT EXISTS media_refs
+(username VARCHAR NOT NULL, fname VARCHAR NOT NULL, sha1 VARCHAR NOT NULL,
+PRIMARY KEY (username, fname))";
+pub const CREATE_MEDIA_REFS_INDEX: &str =
+    "CREATE INDEX IF NOT EXISTS ix_media_refs_sha1 ON media_refs (sha1)";
+
+/// sha1 of the content of path,hex-encoded
+pub fn file_sha1(path: &Path) -> io::Result<String> {
+    let mut hasher = Sha1::new();
+    io::copy(&mut File::open(path)?, &mut hasher)?;
+    Ok(hex::encode(hasher.finalize()))
+}
+
+/// the checksum recorded in column csum of the media database,hex-encoded,
+/// `None` for deleted files
+pub(crate) fn checksum(value: ValueRef) -> Option<String> {
+    match value {
+        ValueRef::Blob(b) if !b.is_empty() => Some(hex::encode(b)),
+        ValueRef::Text(t) if !t.is_empty() => Some(String::from_utf8_lossy(t).to_lowercase()),
+        _ => None,
+    }
+}
+
+/// files present according to the media database of a user,as (file name,
+/// sha1)
+pub fn media_checksums(user_folder: &Path) -> Result<Vec<(String, String)>, rusqlite::Error> {
+    let path = media_db_path(user_folder);
+    if !path.exists() {
+        return Ok(vec![]);
+    }
+    let conn = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+    let mut stmt = conn.prepare("SELECT fname, csum FROM media")?;
+    let rows = stmt.query_map([], |row| Ok((row.get(0)?, checksum(row.get_ref(1)?))))?;
+    let mut files = vec![];
+    for row in rows {
+        let (fname, sha1): (String, Option<String>) = row?;
+        if let Some(sha1) = sha1 {
+            files.push((fname, sha1));
+        }
+    }
+    Ok(files)
+}
+
+#[cfg(unix)]
+fn same_file(a: &fs::Metadata, b: &fs::Metadata) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    a.dev() == b.dev() && a.ino() == b.ino()
+}
+
+#[cfg(not(unix))]
+fn same_file(_a: &fs::Metadata, _b: &fs::Metadata) -> bool {
+    false
+}
+
+/// number of links to a file,1 where it cannot be told
+#[cfg(unix)]
+fn link_count(metadata: &fs::Metadata) -> u64 {
+    use std::os::unix::fs::MetadataExt;
+    metadata.nlink()
+}
+
+#[cfg(not(unix))]
+fn link_count(_metadata: &fs::Metadata) -> u64 {
+    1
+}
+
+/// replace path with a hard link to target,atomically
+fn replace_with_link(target: &Path, path: &Path) -> io::Result<()> {
+    let tmp = path.with_file_name(format!(".link-{}", hex::encode(rand::random::<[u8; 8]>())));
+    fs::hard_link(target, &tmp)?;
+    fs::rename(&tmp, path).map_err(|e| {
+        let _ = fs::remove_file(&tmp);
+        e
+    })
+}
+
+/// outcome of linking a media file to the store
+pub enum Linked {
+    /// linked,with the bytes freed by replacing a copy with a link
+    Saved(u64),
+    /// not in the media folder
+    Missing,
+    /// the content does not match the checksum of the media database
+    Mismatch,
+}
+
+/// outcome of deduplicating the media of a user
+#[derive(Debug, Default)]
+pub struct DedupStats {
+    /// files now linked to the store
+    pub linked: usize,
+    pub missing: usize,
+    /// files whose content did not match the checksum of the media database
+    pub mismatched: usize,
+    /// bytes freed by replacing copies with links
+    pub saved_bytes: u64,
+}
+
+pub struct MediaStore {
+    root: PathBuf,
+    server_db: String,
+}
+
+impl MediaStore {
+    /// the store if `dedup_media` is set
+    pub fn from_config(config: &Config) -> Option<Self> {
+        config.storage().dedup_media.then(|| MediaStore {
+            root: PathBuf::from(config.media_store_path()),
+            server_db: config.server_db_path(),
+        })
+    }
+
+    /// path of the stored file with checksum sha1
+    pub fn path(&self, sha1: &str) -> PathBuf {
+        self.root.join(&sha1[..2.min(sha1.len())]).join(sha1)
+    }
+
+    pub(crate) fn open(&self) -> Result<Connection, rusqlite::Error> {
+        Connection::open(&self.server_db)
+    }
+
+    /// give the files of a user about to be replaced by an `uploadChanges`
+    /// a private copy,changes as returned by `media::upload_changes`.
+    pub fn unshare(
+        &self,
+        user_folder: &Path,
+        changes: &[(String, Option<String>)],
+    ) -> io::Result<()> {
+        let media = media_folder(user_folder);
+        for (fname, entry) in changes {
+            let path = media.join(fname);
+            if entry.is_none() || !path.exists() || link_count(&fs::metadata(&path)?) < 2 {
+                continue;
+            }
+            let tmp = media.join(format!(".copy-{}", hex::encode(rand::random::<[u8; 8]>())));
+            fs::copy(&path, &tmp)?;
+            fs::rename(&tmp, &path)?;
+        }
+        Ok(())
+    }
+
+    /// link the files added by an `uploadChanges` to the store and drop the
+    /// references of the removed ones.
+    pub fn apply_changes(
+        &self,
+        username: &str,
+        user_folder: &Path,
+        changes: &[(String, Option<String>)],
+    ) -> Result<(), ApplicationError> {
+        let conn = self.open()?;
+        let checksums: HashMap<String, String> =
+            media_checksums(user_folder)?.into_iter().collect();
+        let media = media_folder(user_folder);
+        for (fname, entry) in changes {
+            match (entry, checksums.get(fname)) {
+                (Some(_), Some(sha1)) => {
+                    if let Linked::Mismatch = self.link(&conn, username, &media, fname, sha1)? {
+                        // keep the private copy,but not a stale reference
+                        self.release(&conn, username, fname)?;
+                    }
+                }
+                _ => self.release(&conn, username, fname)?,
+            }
+        }
+        Ok(())
+    }
+
+    /// make file fname of the media folder of user a link to the stored file
+    /// with checksum sha1,storing it if it is the first one.
+    pub fn link(
+        &self,
+        conn: &Connection,
+        username: &str,
+        media: &Path,
+        fname: &str,
+        sha1: &str,
+    ) -> Result<Linked, ApplicationError> {
+        let path = media.join(fname);
+        let metadata = match fs::metadata(&path) {
+            Ok(m) => m,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Linked::Missing),
+            Err(e) => return Err(e.into()),
+        };
+        let stored = self.path(sha1);
+        let linked_before = fs::metadata(&stored).map_or(false, |s| same_file(&s, &metadata));
+        if !linked_before && file_sha1(&path)? != sha1 {
+            log::warn!("media file {fname} of user {username} does not match its checksum");
+            return Ok(Linked::Mismatch);
+        }
+        // other processes linking or collecting the same stored file wait
+        // until the file and its references agree again
+        let tx = Transaction::new_unchecked(conn, TransactionBehavior::Immediate)?;
+        let existing = fs::metadata(&stored).ok();
+        let already_linked = existing.as_ref().map_or(false, |s| same_file(s, &metadata));
+        let saved = match existing {
+            _ if already_linked => 0,
+            Some(_) => {
+                replace_with_link(&stored, &path)?;
+                metadata.len()
+            }
+            None => {
+                if let Some(folder) = stored.parent() {
+                    fs::create_dir_all(folder)?;
+                }
+                match fs::hard_link(&path, &stored) {
+                    Ok(()) => 0,
+                    // stored by another user in the meantime
+                    Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                        replace_with_link(&stored, &path)?;
+                        metadata.len()
+                    }
+                    Err(e) => return Err(e.into()),
+                }
+            }
+        };
+        let previous = self.reference(&tx, username, fname)?;
+        tx.execute(
+            "INSERT OR REPLACE INTO media_refs (username, fname, sha1) VALUES (?, ?, ?)",
+            [username, fname, sha1],
+        )?;
+        if let Some(previous) = previous.filter(|p| p != sha1) {
+            self.collect(&tx, &previous)?;
+        }
+        tx.commit()?;
+        Ok(Linked::Saved(saved))
+    }
+
+    /// checksum the file fname of user refers to
+    fn reference(
+        &self,
+        conn: &Connection,
+        username: &str,
+        fname: &str,
+    ) -> Result<Option<String>, rusqlite::Error> {
+        conn.query_row(
+            "SELECT sha1 FROM media_refs WHERE username=? AND fname=?",
+            [username, fname],
+            |row| row.get(0),
+        )
+        .optional()
+    }
+
+    /// drop the reference of file fname of user
+    pub fn release(
+        &self,
+        conn: &Connection,
+        username: &str,
+        fname: &str,
+    ) -> Result<(), ApplicationError> {
+        let tx = Transaction::new_unchecked(conn, TransactionBehavior::Immediate)?;
+        if let Some(sha1) = self.reference(&tx, username, fname)? {
+            tx.execute(
+                "DELETE FROM media_refs WHERE username=? AND fname=?",
+                [username, fname],
+            )?;
+            self.collect(&tx, &sha1)?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// delete the stored file with checksum sha1 if nothing refers to it
+    fn collect(&self, conn: &Connection, sha1: &str) -> Result<(), ApplicationError> {
+        let refs: i64 = conn.query_row(
+            "SELECT count() FROM media_refs WHERE sha1=?",
+            [sha1],
+            |row| row.get(0),
+        )?;
+        if refs == 0 {
+            match fs::remove_file(self.path(sha1)) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// drop the references of users not in usernames (deleted users) and the
+    /// stored files nothing else refers to,return the number of references
+    /// and files and the bytes freed.Only counts them with dry_run.
+    pub fn collect_orphans(
+        &self,
+        usernames: &[String],
+        dry_run: bool,
+    ) -> Result<(usize, usize, u64), ApplicationError> {
+        let mut conn = self.open()?;
+        // keep files from being linked to the store while it is swept
+        let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        let mut orphan_refs = vec![];
+        let mut referenced = HashSet::new();
+        {
+            let mut stmt = tx.prepare("SELECT username, sha1 FROM media_refs")?;
+            let rows = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?;
+            for row in rows {
+                let (username, sha1): (String, String) = row?;
+                if usernames.contains(&username) {
+                    referenced.insert(sha1);
+                } else {
+                    orphan_refs.push(username);
+                }
+            }
+        }
+        orphan_refs.sort();
+        let refs = orphan_refs.len();
+        orphan_refs.dedup();
+        if !dry_run {
+            for username in &orphan_refs {
+                tx.execute("DELETE FROM media_refs WHERE username=?", [username])?;
+            }
+        }
+        let (mut files, mut bytes) = (0, 0);
+        if !self.root.exists() {
+            tx.commit()?;
+            return Ok((refs, files, bytes));
+        }
+        for folder in fs::read_dir(&self.root)? {
+            let folder = folder?;
+            if !folder.file_type()?.is_dir() {
+                continue;
+            }
+            for entry in fs::read_dir(folder.path())? {
+                let entry = entry?;
+                let sha1 = entry.file_name().to_string_lossy().to_string();
+                if referenced.contains(&sha1) {
+                    continue;
+                }
+                files += 1;
+                bytes += entry.metadata()?.len();
+                if !dry_run {
+                    fs::remove_file(entry.path())?;
+                }
+            }
+        }
+        tx.commit()?;
+        Ok((refs, files, bytes))
+    }
+
+    /// link every media file of a user to the store
+    pub fn dedup_user(
+        &self,
+        username: &str,
+        user_folder: &Path,
+    ) -> Result<DedupStats, ApplicationError> {
+        let conn = self.open()?;
+        let media = media_folder(user_folder);
+        let mut stats = DedupStats::default();
+        for (fname, sha1) in media_checksums(user_folder)? {
+            match self.link(&conn, username, &media, &fname, &sha1)? {
+                Linked::Saved(saved) => {
+                    stats.linked += 1;
+                    stats.saved_bytes += saved;
+                }
+                Linked::Missing => stats.missing += 1,
+                Linked::Mismatch => stats.mismatched += 1,
+            }
+        }
+        Ok(stats)
+    }
+}
+
+/// handle `media dedup`,for the given users or all of them
+pub fn dedup_media(config: &Config, usernames: &[String]) -> Result<(), ApplicationError> {
+    let store = MediaStore::from_config(config).ok_or_else(|| {
+        ApplicationError::ParseConfig(
+            "set dedup_media = true in section [storage] before converting media".into(),
+        )
+    })?;
+    let usernames = match usernames {
+        [] => crate::user::user_list(config.auth_db_path())?.unwrap_or_default(),
+        names => names.to_vec(),
+    };
+    let data_root = PathBuf::from(config.data_root_path());
+    let mut total = DedupStats::default();
+    for username in usernames {
+        let stats = store.dedup_user(&username, &data_root.join(&username))?;
+        println!(
+            "{username}: {} files linked,{} bytes freed,{} missing,{} not matching their checksum",
+            stats.linked, stats.saved_bytes, stats.missing, stats.mismatched
+        );
+        total.linked += stats.linked;
+        total.missing += stats.missing;
+        total.mismatched += stats.mismatched;
+        total.saved_bytes += stats.saved_bytes;
+    }
+    println!(
+        "total: {} files linked,{} bytes freed",
+        total.linked, total.saved_bytes
+    );
+    Ok(())
+}
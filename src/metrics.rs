@@ -0,0 +1,252 @@
+//! prometheus metrics,exposed under `/metrics`.
+//!
+//! Counters and histograms are updated while serving requests,gauges of
+//! sessions in progress and storage are computed on each scrape.Metrics are
+//! off unless enabled in section `[metrics]`,and served on the sync listener
+//! unless it sets a listen address of its own.Storage per user reveals the
+//! usernames and is only exported with `per_user` set.
+use crate::collection::collection_size;
+use crate::media::media_usage;
+use crate::server::SyncServer;
+use actix_web::dev::ServerHandle;
+use actix_web::{get, web, App, HttpResponse, HttpServer};
+use anki::sync::collection::protocol::SyncMethod;
+use anki::sync::media::protocol::MediaSyncMethod;
+use lazy_static::lazy_static;
+use prometheus::{
+    exponential_buckets, register_histogram_vec, register_int_counter, register_int_counter_vec,
+    register_int_gauge, register_int_gauge_vec, Encoder, HistogramVec, IntCounter, IntCounterVec,
+    IntGauge, IntGaugeVec, TextEncoder,
+};
+use serde::de::value::StrDeserializer;
+use serde::de::IntoDeserializer;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+lazy_static! {
+    static ref SYNC_REQUESTS: IntCounterVec = register_int_counter_vec!(
+        "ankisyncd_sync_requests_total",
+        "sync requests by endpoint,method and response status",
+        &["endpoint", "method", "status"]
+    )
+    .expect("register metric");
+    static ref REQUEST_BYTES: HistogramVec = register_histogram_vec!(
+        "ankisyncd_request_bytes",
+        "size of sync request bodies as received (wire) and after decompression (decoded)",
+        &["endpoint", "stage"],
+        exponential_buckets(1024.0, 4.0, 10).expect("buckets")
+    )
+    .expect("register metric");
+    static ref RESPONSE_BYTES: HistogramVec = register_histogram_vec!(
+        "ankisyncd_response_bytes",
+        "size of sync response bodies before compression",
+        &["compressed"],
+        exponential_buckets(1024.0, 4.0, 10).expect("buckets")
+    )
+    .expect("register metric");
+    static ref RESPONSE_COMPRESSED_BYTES: IntCounter = register_int_counter!(
+        "ankisyncd_response_compressed_bytes_total",
+        "bytes of zstd-compressed sync responses sent"
+    )
+    .expect("register metric");
+    static ref SYNC_DURATION: HistogramVec = register_histogram_vec!(
+        "ankisyncd_sync_duration_seconds",
+        "time from start to finish or abort of a collection sync",
+        &["outcome"],
+        exponential_buckets(0.1, 2.0, 12).expect("buckets")
+    )
+    .expect("register metric");
+    static ref FULL_SYNCS: IntCounterVec = register_int_counter_vec!(
+        "ankisyncd_full_syncs_total",
+        "full uploads and downloads of collections",
+        &["direction"]
+    )
+    .expect("register metric");
+    static ref AUTH_FAILURES: IntCounter = register_int_counter!(
+        "ankisyncd_auth_failures_total",
+        "failed logins"
+    )
+    .expect("register metric");
+    static ref ACTIVE_SESSIONS: IntGauge = register_int_gauge!(
+        "ankisyncd_active_sessions",
+        "collection syncs in progress"
+    )
+    .expect("register metric");
+    static ref STORAGE: IntGaugeVec = register_int_gauge_vec!(
+        "ankisyncd_storage",
+        "storage used by the loaded users,in bytes for collection and media and as count for media_files",
+        &["kind"]
+    )
+    .expect("register metric");
+    static ref USER_STORAGE: IntGaugeVec = register_int_gauge_vec!(
+        "ankisyncd_user_storage",
+        "storage used per user,in bytes for collection and media and as count for media_files",
+        &["user", "kind"]
+    )
+    .expect("register metric");
+    // start time of the collection syncs in progress,by username
+    static ref SESSION_STARTS: Mutex<HashMap<String, Instant>> = Mutex::new(HashMap::new());
+}
+
+/// method label of a request,the path segment if it names a sync method of
+/// endpoint and `unknown` otherwise,so that clients cannot make up label values
+fn method_label<'a>(endpoint: &str, method: &'a str) -> &'a str {
+    let de: StrDeserializer<serde::de::value::Error> = method.into_deserializer();
+    let known = match endpoint {
+        "msync" => MediaSyncMethod::deserialize(de).is_ok(),
+        _ => SyncMethod::deserialize(de).is_ok(),
+    };
+    if known {
+        method
+    } else {
+        "unknown"
+    }
+}
+
+/// count a sync request once its response is ready,method as given in the path
+pub fn observe_request(
+    endpoint: &str,
+    method: &str,
+    status: u16,
+    wire_bytes: Option<u64>,
+    decoded_bytes: usize,
+) {
+    SYNC_REQUESTS
+        .with_label_values(&[
+            endpoint,
+            method_label(endpoint, method),
+            &status.to_string(),
+        ])
+        .inc();
+    if let Some(bytes) = wire_bytes {
+        REQUEST_BYTES
+            .with_label_values(&[endpoint, "wire"])
+            .observe(bytes as f64);
+    }
+    REQUEST_BYTES
+        .with_label_values(&[endpoint, "decoded"])
+        .observe(decoded_bytes as f64);
+}
+
+pub fn observe_response(bytes: usize, compressed: bool) {
+    RESPONSE_BYTES
+        .with_label_values(&[if compressed { "true" } else { "false" }])
+        .observe(bytes as f64);
+}
+
+pub fn add_compressed_response_bytes(bytes: usize) {
+    RESPONSE_COMPRESSED_BYTES.inc_by(bytes as u64);
+}
+
+pub fn auth_failed() {
+    AUTH_FAILURES.inc();
+}
+
+pub fn full_sync(direction: &str) {
+    FULL_SYNCS.with_label_values(&[direction]).inc();
+}
+
+pub fn session_started(username: &str) {
+    if let Ok(mut starts) = SESSION_STARTS.lock() {
+        starts.insert(username.to_string(), Instant::now());
+    }
+}
+
+/// record the duration of the sync of user,outcome is `finish` or `abort`
+pub fn session_ended(username: &str, outcome: &str) {
+    let started = SESSION_STARTS
+        .lock()
+        .ok()
+        .and_then(|mut starts| starts.remove(username));
+    if let Some(started) = started {
+        SYNC_DURATION
+            .with_label_values(&[outcome])
+            .observe(started.elapsed().as_secs_f64());
+    }
+}
+
+/// whether storage is exported per user,as set by `per_user`
+pub struct PerUser(pub bool);
+
+/// refresh the gauges computed on scrape
+fn update_gauges(server: &SyncServer, base_folder: &Path, per_user: bool) {
+    ACTIVE_SESSIONS.set(server.sessions_in_progress() as i64);
+    USER_STORAGE.reset();
+    let mut totals = [0u64; 3];
+    for username in server.usernames() {
+        let folder = base_folder.join(&username);
+        let collection = collection_size(&folder);
+        let (files, bytes) = match media_usage(&folder) {
+            Ok(usage) => usage,
+            Err(e) => {
+                log::error!("failed to read media db of user {username}: {e}");
+                (0, 0)
+            }
+        };
+        let usage = [collection, bytes, files];
+        if per_user {
+            for (kind, value) in STORAGE_KINDS.iter().zip(usage) {
+                USER_STORAGE
+                    .with_label_values(&[&username, kind])
+                    .set(value as i64);
+            }
+        }
+        for (total, value) in totals.iter_mut().zip(usage) {
+            *total += value;
+        }
+    }
+    for (kind, total) in STORAGE_KINDS.iter().zip(totals) {
+        STORAGE.with_label_values(&[kind]).set(total as i64);
+    }
+}
+
+/// values of label kind of the storage gauges
+const STORAGE_KINDS: [&str; 3] = ["collection", "media", "media_files"];
+
+#[get("/metrics")]
+pub async fn metrics(
+    server: web::Data<Arc<SyncServer>>,
+    base_folder: web::Data<PathBuf>,
+    per_user: web::Data<PerUser>,
+) -> HttpResponse {
+    let gauges = web::block(move || update_gauges(&server, &base_folder, per_user.0)).await;
+    if let Err(e) = gauges {
+        log::error!("failed to update metrics: {e}");
+    }
+    let encoder = TextEncoder::new();
+    let mut body = vec![];
+    if let Err(e) = encoder.encode(&prometheus::gather(), &mut body) {
+        log::error!("failed to encode metrics: {e}");
+        return HttpResponse::InternalServerError().finish();
+    }
+    HttpResponse::Ok()
+        .content_type(encoder.format_type())
+        .body(body)
+}
+
+/// serve `/metrics` on a listen address of its own,the returned handle stops it.
+pub fn spawn_metrics_server(
+    listen: &str,
+    server: web::Data<Arc<SyncServer>>,
+    base_folder: web::Data<PathBuf>,
+    per_user: web::Data<PerUser>,
+) -> std::io::Result<ServerHandle> {
+    let metrics_server = HttpServer::new(move || {
+        App::new()
+            .app_data(server.clone())
+            .app_data(base_folder.clone())
+            .app_data(per_user.clone())
+            .service(metrics)
+    })
+    .workers(1)
+    .disable_signals()
+    .bind(listen)?
+    .run();
+    let handle = metrics_server.handle();
+    actix_web::rt::spawn(metrics_server);
+    log::info!("serving metrics on {listen}");
+    Ok(handle)
+}
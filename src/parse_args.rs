@@ -1,5 +1,10 @@
+use crate::backup::backup_manage;
+use crate::check::check_user;
 use crate::config::Config;
 use crate::error::ApplicationError;
+use crate::media_gc::gc_media;
+use crate::media_store::dedup_media;
+use crate::sync_log::print_sync_log;
 use crate::user::user_manage;
 use clap::Parser;
 use std::path::PathBuf;
@@ -12,6 +17,27 @@ pub struct Arg {
     /// Show the default configuration
     #[clap(short, long, action)]
     pub(crate) default: bool,
+    /// Show the configuration in effect after env vars and flags,secrets masked
+    #[clap(long, action)]
+    pub(crate) print_effective_config: bool,
+    /// listen address,overrides listen.host
+    #[clap(long, value_name("host"))]
+    pub(crate) host: Option<String>,
+    /// listen port,overrides listen.port
+    #[clap(long, value_name("port"))]
+    pub(crate) port: Option<u16>,
+    /// folder of server data,overrides paths.root_dir
+    #[clap(long, value_name("dir"))]
+    pub(crate) root_dir: Option<String>,
+    /// text or json,overrides logging.format
+    #[clap(long, value_name("format"))]
+    pub(crate) log_format: Option<String>,
+    /// size limit of a sync request in megabytes,overrides limits.max_sync_payload_megs
+    #[clap(long, value_name("megs"))]
+    pub(crate) max_sync_payload_megs: Option<u64>,
+    /// set any config value,may be repeated,i.e. --set backup.keep_last=5
+    #[clap(long = "set", value_name("section.key=value"))]
+    pub(crate) set: Vec<String>,
     #[command(subcommand)]
     pub(crate) cmd: Option<UserCommand>,
 }
@@ -31,20 +57,155 @@ pub enum UserCommand {
         /// list all usernames extracted from db ,i.e.ankisyncd user  -l
         #[clap(short, long, action)]
         list: bool,
+        /// list devices holding a host key of user,i.e.ankisyncd user --devices username
+        #[clap(long, value_parser, value_name("username"))]
+        devices: Option<String>,
+        /// revoke the host key of a device listed by --devices,i.e.ankisyncd user --revoke username device
+        #[clap(long, value_parser,number_of_values(2),value_names(&["username", "device"]))]
+        revoke: Option<Vec<String>>,
+        /// set storage quota of user,sizes in megabytes and 0 for unlimited,i.e.ankisyncd user --quota username 100 2000 10000
+        #[clap(long, value_parser,number_of_values(4),value_names(&["username", "collection_megs", "media_megs", "media_files"]))]
+        quota: Option<Vec<String>>,
+        /// lift the login lockout of users or client ips,i.e.ankisyncd user --unlock username 192.0.2.1
+        #[clap(long, value_parser, value_name("username|ip"), num_args(1..))]
+        unlock: Option<Vec<String>>,
+    },
+    /// show the recent syncs of user,newest first,i.e.ankisyncd log username
+    Log {
+        username: String,
+        /// number of syncs shown
+        #[clap(short = 'n', long, default_value_t = 20)]
+        limit: usize,
+    },
+    /// server-side collection backups
+    Backup {
+        #[command(subcommand)]
+        action: BackupCommand,
+    },
+    /// run the database check on the collection of user and report the problems found,i.e.ankisyncd check username
+    Check {
+        username: String,
+        /// repair the collection,the user is locked out of syncing meanwhile
+        #[clap(long, action)]
+        fix: bool,
+    },
+    /// media files kept on the server
+    Media {
+        #[command(subcommand)]
+        action: MediaCommand,
+    },
+}
+#[derive(clap::Subcommand, Debug)]
+pub enum BackupCommand {
+    /// list the backups of user,newest first,i.e.ankisyncd backup list username
+    List { username: String },
+    /// back up the collection of user now,i.e.ankisyncd backup create username
+    Create { username: String },
+    /// replace the collection of user with a backup,clients are forced into a full download,i.e.ankisyncd backup restore username 20230101-120000.000
+    Restore { username: String, id: String },
+}
+#[derive(clap::Subcommand, Debug)]
+pub enum MediaCommand {
+    /// link the media files of users (all of them if none given) to the shared media store,needs dedup_media,i.e.ankisyncd media dedup username
+    Dedup { usernames: Vec<String> },
+    /// reconcile the media files of users with their media database,delete unreferenced files and drop the entries of missing or corrupt ones,i.e.ankisyncd media gc username --dry-run
+    Gc {
+        #[clap(required_unless_present = "all", conflicts_with = "all")]
+        usernames: Vec<String>,
+        /// all users,also dropping what the media store keeps for deleted users
+        #[clap(long, action)]
+        all: bool,
+        /// only report,change nothing
+        #[clap(long, action)]
+        dry_run: bool,
+        /// download and check the content of media files the storage keeps no
+        /// checksum for
+        #[clap(long, action)]
+        verify_content: bool,
     },
 }
 
-/// Get config from path (if specified) or default value,
+/// Get config from path (if specified) or default value,with env vars and flags applied
 pub fn config_from_arguments(arg: &Arg) -> Result<Config, ApplicationError> {
-    if let Some(p) = arg.config.as_ref() {
-        return Config::from_file(p);
+    let conf = match arg.config.as_ref() {
+        Some(p) => Config::from_file(p)?,
+        None => Config::default(),
+    };
+    apply_overrides(conf, arg)
+}
+
+/// apply env vars and then flags to conf,see the precedence in the README
+pub fn apply_overrides(conf: Config, arg: &Arg) -> Result<Config, ApplicationError> {
+    conf.with_overrides(&Config::env_overrides())?
+        .with_overrides(&flag_overrides(arg)?)
+}
+
+/// overrides given by flags as (`section.key`,value),`--set` last
+fn flag_overrides(arg: &Arg) -> Result<Vec<(String, String)>, ApplicationError> {
+    let mut overrides = vec![];
+    let flags = [
+        ("listen.host", arg.host.clone()),
+        ("listen.port", arg.port.map(|p| p.to_string())),
+        ("paths.root_dir", arg.root_dir.clone()),
+        ("logging.format", arg.log_format.clone()),
+        (
+            "limits.max_sync_payload_megs",
+            arg.max_sync_payload_megs.map(|m| m.to_string()),
+        ),
+    ];
+    for (key, value) in flags {
+        if let Some(v) = value {
+            overrides.push((key.to_string(), v));
+        }
+    }
+    for s in &arg.set {
+        let (key, value) = s.split_once('=').ok_or_else(|| {
+            ApplicationError::ParseConfig(format!("--set {s}: expected section.key=value"))
+        })?;
+        overrides.push((key.trim().to_string(), value.to_string()));
     }
-    Ok(Config::default())
+    Ok(overrides)
 }
 
 /// Manage user
-pub fn manage_user(cmd: &UserCommand, auth_path: &str) {
-    if let Err(e) = user_manage(cmd, auth_path) {
-        panic!("Error managing users: {e}");
-    };
+pub fn manage_user(cmd: &UserCommand, conf: &Config) {
+    match cmd {
+        UserCommand::Backup { action } => {
+            if let Err(e) = backup_manage(action, conf) {
+                panic!("Error managing backups: {e}");
+            }
+        }
+        UserCommand::Check { username, fix } => {
+            if let Err(e) = check_user(conf, username, *fix) {
+                panic!("Error checking collection: {e}");
+            }
+        }
+        UserCommand::Media { action } => match action {
+            MediaCommand::Dedup { usernames } => {
+                if let Err(e) = dedup_media(conf, usernames) {
+                    panic!("Error deduplicating media: {e}");
+                }
+            }
+            MediaCommand::Gc {
+                usernames,
+                all,
+                dry_run,
+                verify_content,
+            } => {
+                if let Err(e) = gc_media(conf, usernames, *all, *dry_run, *verify_content) {
+                    panic!("Error collecting media: {e}");
+                }
+            }
+        },
+        UserCommand::Log { username, limit } => {
+            if let Err(e) = print_sync_log(conf.server_db_path(), username, *limit) {
+                panic!("Error reading sync log: {e}");
+            }
+        }
+        UserCommand::User { .. } => {
+            if let Err(e) = user_manage(cmd, conf.auth_db_path(), conf.hashing()) {
+                panic!("Error managing users: {e}");
+            };
+        }
+    }
 }
@@ -0,0 +1,320 @@
+//! client ip of requests passed on by trusted reverse proxies.
+//!
+//! Behind a reverse proxy the peer of every connection is the proxy itself.If
+//! the peer is listed in `trusted_proxies` of section `[listen]`,the client ip
+//! is taken from header `Forwarded` (or `X-Forwarded-For` without it):hops are
+//! walked from the nearest one and the first address that is not a trusted
+//! proxy is the client.Headers sent by other peers are ignored,clients could
+//! put anything in them.
+//!
+//! Proxies passing on connections rather than requests (i.e. HAProxy in tcp
+//! mode) can send the client address in a PROXY protocol header instead,with
+//! `proxy_protocol` set the listener reads the header (version 1 or 2) off
+//! every connection of a trusted proxy,before any TLS handshake,and requests
+//! see the client as their peer.Connections of trusted proxies without a
+//! header are refused.
+use crate::error::ApplicationError;
+use actix_http::error::DispatchError;
+use actix_http::{body::MessageBody, HttpService, Protocol, Request, Response};
+use actix_server::{Server, ServerBuilder};
+use actix_service::{
+    fn_service, map_config, IntoServiceFactory, Service, ServiceFactory, ServiceFactoryExt,
+};
+use actix_web::dev::AppConfig;
+use actix_web::http::header::{HeaderMap, FORWARDED, X_FORWARDED_FOR};
+use actix_web::rt::net::TcpStream;
+use actix_web::rt::time::timeout;
+use actix_web::{web, HttpRequest};
+use ipnet::IpNet;
+use std::fmt;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite};
+
+/// signature starting a version 2 header
+const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+/// longest version 1 header,line break included
+const V1_MAX_LEN: usize = 107;
+/// how long a trusted proxy may take to send the header
+const HEADER_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// networks whose forwarding headers are trusted
+#[derive(Debug, Clone, Default)]
+pub struct TrustedProxies(Vec<IpNet>);
+
+impl TrustedProxies {
+    /// parse CIDRs (i.e. `10.0.0.0/8`) and single addresses.
+    pub fn parse(entries: &[String]) -> Result<Self, ApplicationError> {
+        entries
+            .iter()
+            .map(|e| {
+                e.parse::<IpNet>()
+                    .or_else(|_| e.parse::<IpAddr>().map(IpNet::from))
+                    .map_err(|_| {
+                        ApplicationError::ParseConfig(format!("invalid trusted proxy {e}"))
+                    })
+            })
+            .collect::<Result<Vec<_>, _>>()
+            .map(TrustedProxies)
+    }
+
+    fn contains(&self, ip: &IpAddr) -> bool {
+        self.0.iter().any(|net| net.contains(ip))
+    }
+
+    /// the client ip of a request that came from peer
+    pub fn resolve(&self, peer: IpAddr, headers: &HeaderMap) -> IpAddr {
+        if !self.contains(&peer) {
+            return peer;
+        }
+        let hops = forwarded_hops(headers);
+        let mut client = peer;
+        for hop in hops.iter().rev() {
+            match hop {
+                Some(ip) => {
+                    client = *ip;
+                    if !self.contains(ip) {
+                        break;
+                    }
+                }
+                // obfuscated or garbled,the nearest known hop is all we have
+                None => break,
+            }
+        }
+        client
+    }
+}
+
+/// parse an address as found in forwarding headers,with or without port
+fn parse_hop(value: &str) -> Option<IpAddr> {
+    let value = value.trim().trim_matches('"');
+    if let Ok(ip) = value.parse::<IpAddr>() {
+        return Some(ip);
+    }
+    if let Ok(addr) = value.parse::<SocketAddr>() {
+        return Some(addr.ip());
+    }
+    // `[2001:db8::1]` without port
+    value
+        .strip_prefix('[')
+        .and_then(|v| v.strip_suffix(']'))
+        .and_then(|v| v.parse().ok())
+}
+
+/// the hops listed by the forwarding headers,client first
+fn forwarded_hops(headers: &HeaderMap) -> Vec<Option<IpAddr>> {
+    let forwarded: Vec<_> = headers
+        .get_all(FORWARDED)
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .map(|element| {
+            element
+                .split(';')
+                .filter_map(|pair| pair.split_once('='))
+                .find(|(name, _)| name.trim().eq_ignore_ascii_case("for"))
+                .and_then(|(_, value)| parse_hop(value))
+        })
+        .collect();
+    if !forwarded.is_empty() {
+        return forwarded;
+    }
+    headers
+        .get_all(X_FORWARDED_FOR)
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .map(parse_hop)
+        .collect()
+}
+
+/// the ip of the client that sent req,`None` if the peer address is unknown.
+pub fn client_ip(req: &HttpRequest) -> Option<IpAddr> {
+    let peer = req.peer_addr()?.ip();
+    let ip = match req.app_data::<web::Data<TrustedProxies>>() {
+        Some(trusted) => trusted.resolve(peer, req.headers()),
+        None => peer,
+    };
+    Some(ip)
+}
+
+fn malformed(what: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("PROXY protocol header {what}"),
+    )
+}
+
+/// client address of a version 1 header,line break stripped.`None` for
+/// connections of unknown protocols,which are their own client.
+fn parse_v1(line: &[u8]) -> io::Result<Option<SocketAddr>> {
+    let line = std::str::from_utf8(line).map_err(|_| malformed("is not text"))?;
+    let fields: Vec<_> = line.split(' ').collect();
+    match fields[..] {
+        ["PROXY", "UNKNOWN", ..] => Ok(None),
+        ["PROXY", "TCP4" | "TCP6", source, _, port, _] => {
+            let ip: IpAddr = source
+                .parse()
+                .map_err(|_| malformed("has an invalid address"))?;
+            let port = port.parse().map_err(|_| malformed("has an invalid port"))?;
+            Ok(Some(SocketAddr::new(ip, port)))
+        }
+        _ => Err(malformed("is malformed")),
+    }
+}
+
+/// client address of a version 2 header,given the version and command byte,
+/// the family byte and the address block.`None` for health checks of the
+/// proxy itself (command LOCAL) and families other than inet.
+fn parse_v2(version: u8, family: u8, addresses: &[u8]) -> io::Result<Option<SocketAddr>> {
+    match version {
+        0x20 => return Ok(None),
+        0x21 => {}
+        _ => return Err(malformed("has an unknown version or command")),
+    }
+    let port = |at: usize| u16::from_be_bytes([addresses[at], addresses[at + 1]]);
+    match (family >> 4, addresses.len()) {
+        (1, len) if len >= 12 => {
+            let ip = Ipv4Addr::new(addresses[0], addresses[1], addresses[2], addresses[3]);
+            Ok(Some(SocketAddr::new(ip.into(), port(8))))
+        }
+        (2, len) if len >= 36 => {
+            let mut ip = [0; 16];
+            ip.copy_from_slice(&addresses[..16]);
+            Ok(Some(SocketAddr::new(Ipv6Addr::from(ip).into(), port(32))))
+        }
+        // unspecified or unix sockets
+        (0 | 3, _) => Ok(None),
+        _ => Err(malformed("has a truncated address")),
+    }
+}
+
+/// read the header off io,reading no further
+async fn read_header<T: AsyncRead + Unpin>(io: &mut T) -> io::Result<Option<SocketAddr>> {
+    let mut start = [0; 12];
+    io.read_exact(&mut start).await?;
+    if start == V2_SIGNATURE {
+        let mut head = [0; 4];
+        io.read_exact(&mut head).await?;
+        let mut addresses = vec![0; u16::from_be_bytes([head[2], head[3]]) as usize];
+        io.read_exact(&mut addresses).await?;
+        return parse_v2(head[0], head[1], &addresses);
+    }
+    if !start.starts_with(b"PROXY ") {
+        return Err(malformed("is missing"));
+    }
+    let mut line = start.to_vec();
+    while !line.ends_with(b"\r\n") {
+        if line.len() >= V1_MAX_LEN {
+            return Err(malformed("is too long"));
+        }
+        line.push(io.read_u8().await?);
+    }
+    parse_v1(&line[..line.len() - 2])
+}
+
+/// the address of the client of a connection,from the header if the peer is
+/// a trusted proxy
+async fn client_addr(io: &mut TcpStream, trusted: &TrustedProxies) -> io::Result<SocketAddr> {
+    let peer = io.peer_addr()?;
+    if !trusted.contains(&peer.ip()) {
+        return Ok(peer);
+    }
+    let header = timeout(HEADER_TIMEOUT, read_header(io))
+        .await
+        .map_err(|_| malformed("did not arrive in time"))?;
+    match header {
+        Ok(client) => Ok(client.unwrap_or(peer)),
+        Err(e) => {
+            log::warn!("refused connection of trusted proxy {peer}: {e}");
+            Err(e)
+        }
+    }
+}
+
+/// the http service of the apps factory makes,on connections of type T
+fn http_service<F, I, S, B, T>(
+    factory: &F,
+) -> impl ServiceFactory<
+    (T, Protocol, Option<SocketAddr>),
+    Config = (),
+    Response = (),
+    Error = DispatchError,
+    InitError = (),
+>
+where
+    F: Fn() -> I,
+    I: IntoServiceFactory<S, Request>,
+    S: ServiceFactory<Request, Config = AppConfig> + 'static,
+    S::Error: Into<actix_web::Error> + 'static,
+    S::InitError: fmt::Debug,
+    S::Response: Into<Response<B>> + 'static,
+    <S::Service as Service<Request>>::Future: 'static,
+    S::Service: 'static,
+    B: MessageBody + 'static,
+    T: AsyncRead + AsyncWrite + Unpin + 'static,
+{
+    let app = factory().into_factory().map_err(|e: S::Error| {
+        let e: actix_web::Error = e.into();
+        e.error_response()
+    });
+    HttpService::build().finish(map_config(app, |_| AppConfig::default()))
+}
+
+/// bind a server for the apps factory makes that reads the PROXY protocol
+/// header of trusted proxies,as `HttpServer` would bind it otherwise.
+pub fn bind<F, I, S, B>(
+    factory: F,
+    listen: &str,
+    trusted: TrustedProxies,
+    #[cfg(feature = "tls")] tls: Option<rustls::ServerConfig>,
+) -> io::Result<ServerBuilder>
+where
+    F: Fn() -> I + Send + Clone + 'static,
+    I: IntoServiceFactory<S, Request>,
+    S: ServiceFactory<Request, Config = AppConfig> + 'static,
+    S::Error: Into<actix_web::Error> + 'static,
+    S::InitError: fmt::Debug,
+    S::Response: Into<Response<B>> + 'static,
+    <S::Service as Service<Request>>::Future: 'static,
+    S::Service: 'static,
+    B: MessageBody + 'static,
+{
+    let trusted = Arc::new(trusted);
+    #[cfg(feature = "tls")]
+    if let Some(mut config) = tls {
+        config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+        let acceptor = tokio_rustls::TlsAcceptor::from(Arc::new(config));
+        return Server::build().bind("ankisyncd", listen, move || {
+            let (trusted, acceptor) = (trusted.clone(), acceptor.clone());
+            fn_service(move |mut io: TcpStream| {
+                let (trusted, acceptor) = (trusted.clone(), acceptor.clone());
+                async move {
+                    let client = client_addr(&mut io, &trusted)
+                        .await
+                        .map_err(DispatchError::Io)?;
+                    let io = acceptor.accept(io).await.map_err(DispatchError::Io)?;
+                    let protocol = match io.get_ref().1.alpn_protocol() {
+                        Some(b"h2") => Protocol::Http2,
+                        _ => Protocol::Http1,
+                    };
+                    Ok::<_, DispatchError>((io, protocol, Some(client)))
+                }
+            })
+            .and_then(http_service(&factory))
+        });
+    }
+    Server::build().bind("ankisyncd", listen, move || {
+        let trusted = trusted.clone();
+        fn_service(move |mut io: TcpStream| {
+            let trusted = trusted.clone();
+            async move {
+                let client = client_addr(&mut io, &trusted)
+                    .await
+                    .map_err(DispatchError::Io)?;
+                Ok::<_, DispatchError>((io, Protocol::Http1, Some(client)))
+            }
+        })
+        .and_then(http_service(&factory))
+    })
+}
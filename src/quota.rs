@@ -0,0 +1,62 @@
+//! enforcement of the per-user storage quotas stored in auth db.
+use crate::error::ApplicationError;
+use crate::media::usage_after_upload;
+use crate::user::user_quota;
+use std::path::Path;
+
+const MEGABYTE: f64 = 1024.0 * 1024.0;
+
+fn megs(bytes: u64) -> String {
+    format!("{:.1} MB", bytes as f64 / MEGABYTE)
+}
+
+/// reject a full collection upload larger than the collection quota of user.
+pub fn check_collection_upload(
+    auth_db: &str,
+    username: &str,
+    size: u64,
+) -> Result<(), ApplicationError> {
+    let quota = user_quota(username, auth_db)
+        .map_err(|e| ApplicationError::InternalServerError(e.to_string()))?;
+    match quota.collection_bytes {
+        Some(limit) if size > limit => Err(ApplicationError::QuotaExceeded(format!(
+            "Collection of {} exceeds the quota of {} for user {username}",
+            megs(size),
+            megs(limit)
+        ))),
+        _ => Ok(()),
+    }
+}
+
+/// reject media changes that would take user over its media size or file
+/// count quota.
+pub fn check_media_upload(
+    auth_db: &str,
+    username: &str,
+    user_folder: &Path,
+    zip_path: &Path,
+) -> Result<(), ApplicationError> {
+    let quota = user_quota(username, auth_db)
+        .map_err(|e| ApplicationError::InternalServerError(e.to_string()))?;
+    if quota.media_bytes.is_none() && quota.media_files.is_none() {
+        return Ok(());
+    }
+    let (files, bytes) = usage_after_upload(user_folder, zip_path)?;
+    if let Some(limit) = quota.media_bytes {
+        if bytes > limit {
+            return Err(ApplicationError::QuotaExceeded(format!(
+                "Media would take {} which exceeds the quota of {} for user {username}",
+                megs(bytes),
+                megs(limit)
+            )));
+        }
+    }
+    if let Some(limit) = quota.media_files {
+        if files > limit {
+            return Err(ApplicationError::QuotaExceeded(format!(
+                "{files} media files exceed the quota of {limit} files for user {username}"
+            )));
+        }
+    }
+    Ok(())
+}
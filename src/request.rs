@@ -3,72 +3,119 @@
 // https://github.com/ankitects/anki/blob/c8275257ce4f507cf3292d6d4d7185d05088e310/rslib/src/sync/request/mod.rs
 // And middleware method reference to https://github.com/actix/examples/blob/db2edcaeb1fdf8c609e42f4e569122ef5d8ae613/middleware/middleware-ext-mut/src/add_msg.rs
 use actix_web::{
+    body::{BodySize, MessageBody},
     dev::{self, Service, ServiceRequest, ServiceResponse, Transform},
-    Error, HttpMessage,
+    http::{header::CONTENT_LENGTH, Method, StatusCode},
+    web, Error, HttpMessage,
 };
+use anki::sync::error::HttpError;
+use anki::sync::request::header_and_stream::{SyncHeader, SYNC_HEADER_NAME};
 use anki::sync::request::multipart::decode_gzipped_data;
 use anki::sync::request::SyncRequest;
+use anki::sync::response::ORIGINAL_SIZE;
 use anki::sync::version::SyncVersion;
-use anki::sync::{http_server::user::User, request::header_and_stream::SyncHeader};
 use anki::sync::{
     login::{HostKeyRequest, HostKeyResponse},
     request::header_and_stream::decode_zstd_body_for_server,
 };
-use async_std::io::WriteExt;
 use futures_util::{future::LocalBoxFuture, TryStreamExt};
-use std::net::IpAddr;
+use std::net::{IpAddr, Ipv4Addr};
+use std::path::PathBuf;
 use std::{
-    collections::HashMap,
     future::{ready, Ready},
     rc::Rc,
 };
 
 use crate::{
+    app_config::AuthSnapshot,
+    config::{Config, ConfigHashing, ConfigLimits, ConfigSecurity},
+    db::fetch_hash,
     error::ApplicationError,
-    user::{compute_hash, UserError},
+    hostkey::issue_host_key,
+    lockout::{check_login, record_login},
+    logging, metrics, proxy,
+    server::off_worker,
+    spool::{
+        gunzip, is_spooled, max_payload_bytes, spool_field, spool_zstd, SpoolTarget, SpooledBody,
+    },
+    sync_log::{record_sync_event, SyncEvent, SyncUser},
+    user::{create_pass_hash, is_legacy_hash, store_hash, verify_password, UserError},
 };
-/// Get the full field data as text.
-async fn text(mut field: actix_multipart::Field) -> String {
-    // Field in turn is stream of *Bytes* object
-    let mut c: String = String::new();
-    while let Some(chunk) = field.try_next().await.unwrap() {
-        c = String::from_utf8(chunk.to_vec()).unwrap();
+/// map an error of anki decoding a request body to the status it deserves
+fn decode_error(e: HttpError) -> ApplicationError {
+    match e.code {
+        StatusCode::PAYLOAD_TOO_LARGE => ApplicationError::PayloadTooLarge(e.to_string()),
+        StatusCode::NOT_IMPLEMENTED => ApplicationError::UnsupportedSyncVersion(e.to_string()),
+        _ => ApplicationError::BadRequest(e.to_string()),
     }
-    c
 }
-async fn bytes(mut field: actix_multipart::Field) -> Vec<u8> {
+/// Get the full field data as text.
+async fn text(field: actix_multipart::Field) -> Result<String, ApplicationError> {
+    let b = bytes(field).await?;
+    String::from_utf8(b).map_err(|e| ApplicationError::BadRequest(format!("multipart field: {e}")))
+}
+async fn bytes(mut field: actix_multipart::Field) -> Result<Vec<u8>, ApplicationError> {
+    // Field in turn is stream of *Bytes* object
     let mut b = vec![];
-    while let Some(chunk) = field.try_next().await.unwrap() {
-        b.write_all(&chunk).await.unwrap();
-        //  b.write_all(&chunk);
+    while let Some(chunk) = field
+        .try_next()
+        .await
+        .map_err(|e| ApplicationError::BadRequest(format!("multipart field: {e}")))?
+    {
+        b.extend_from_slice(&chunk);
     }
-    b
+    Ok(b)
 }
 pub(super) async fn from_multipart<T>(
     ip: IpAddr,
     mut multipart: actix_multipart::Multipart,
-) -> anki::sync::request::SyncRequest<T> {
+    spool: Option<&SpoolTarget>,
+    bodyless_begin: bool,
+) -> Result<(anki::sync::request::SyncRequest<T>, Option<SpooledBody>), ApplicationError> {
     //reference : https://github.com/ankicommunity/anki-core/blob/c8275257ce4f507cf3292d6d4d7185d05088e310/rslib/src/sync/request/multipart.rs
     let mut host_key = String::new();
     let mut session_key = String::new();
     let mut media_client_version = None;
     let mut compressed = false;
     let mut data = None;
-    // this will cause error when client requesting a media begin get request,so we disregard error condition
-    while let Ok(Some(field)) = multipart.try_next().await {
+    let mut spooled = None;
+    let mut fields = 0;
+    loop {
+        let field = match multipart.try_next().await {
+            Ok(Some(field)) => field,
+            Ok(None) => break,
+            // a media begin get request has no multipart body,its host key is
+            // taken from the query
+            Err(_) if bodyless_begin && fields == 0 => break,
+            Err(e) => return Err(ApplicationError::BadRequest(format!("multipart: {e}"))),
+        };
+        fields += 1;
         match field.name() {
             "c" => {
                 // normal syncs should always be compressed, but media syncs may compress the
                 // zip instead
-                let c = text(field).await;
+                let c = text(field).await?;
                 compressed = c != "0";
             }
             "k" | "sk" => {
-                host_key = text(field).await;
+                host_key = text(field).await?;
             }
-            "s" => session_key = text(field).await,
-            "v" => media_client_version = Some(text(field).await),
-            "data" => data = Some(bytes(field).await),
+            "s" => session_key = text(field).await?,
+            "v" => media_client_version = Some(text(field).await?),
+            "data" => match spool {
+                // clients send the host key first
+                Some(_) if host_key.is_empty() => {
+                    return Err(ApplicationError::BadRequest(
+                        "field data sent before the host key k".to_string(),
+                    ))
+                }
+                Some(target) => {
+                    let folder = target.folder(&host_key).await?;
+                    let limit = target.max_payload_bytes;
+                    spooled = Some((spool_field(field, &folder, limit).await?, limit));
+                }
+                None => data = Some(bytes(field).await?),
+            },
             _ => {}
         };
     }
@@ -79,12 +126,18 @@ pub(super) async fn from_multipart<T>(
             // AnkiDroid omits 'data' when downloading
             b"{}".to_vec()
         } else if compressed {
-            decode_gzipped_data(data.into()).await.unwrap()
+            decode_gzipped_data(data.into())
+                .await
+                .map_err(decode_error)?
         } else {
             data.to_vec()
         }
     };
-    SyncRequest {
+    let spooled = match spooled {
+        Some((body, limit)) if compressed => Some(gunzip(body, limit)?),
+        body => body.map(|(body, _)| body),
+    };
+    let request = SyncRequest {
         ip,
         sync_key: host_key,
         session_key,
@@ -94,17 +147,34 @@ pub(super) async fn from_multipart<T>(
         // may be lower - the old protocol didn't provide the version on every request
         sync_version: SyncVersion(anki::sync::version::SYNC_VERSION_10_V2_TIMEZONE),
         client_version: String::new(),
-    }
+    };
+    Ok((request, spooled))
 }
 pub(super) async fn from_header_and_stream<T>(
     sync_header: SyncHeader,
     body_stream: actix_web::dev::Payload,
     ip: IpAddr,
-) -> anki::sync::request::SyncRequest<T> {
-    sync_header.sync_version.ensure_supported().unwrap();
+    spool: Option<&SpoolTarget>,
+) -> Result<(anki::sync::request::SyncRequest<T>, Option<SpooledBody>), ApplicationError> {
+    sync_header
+        .sync_version
+        .ensure_supported()
+        .map_err(decode_error)?;
 
-    let data = decode_zstd_body_for_server(body_stream).await.ok().unwrap();
-    SyncRequest {
+    let (data, spooled) = match spool {
+        Some(target) => {
+            let folder = target.folder(&sync_header.sync_key).await?;
+            let limit = target.max_payload_bytes;
+            (vec![], Some(spool_zstd(body_stream, &folder, limit).await?))
+        }
+        None => {
+            let data = decode_zstd_body_for_server(body_stream)
+                .await
+                .map_err(decode_error)?;
+            (data, None)
+        }
+    };
+    let request = SyncRequest {
         data,
         json_output_type: std::marker::PhantomData,
         ip,
@@ -113,6 +183,49 @@ pub(super) async fn from_header_and_stream<T>(
         media_client_version: None,
         sync_version: sync_header.sync_version,
         client_version: sync_header.client_ver,
+    };
+    Ok((request, spooled))
+}
+
+/// the sync header of a request,`None` for clients using the multipart protocol
+fn sync_header(req: &ServiceRequest) -> Result<Option<SyncHeader>, ApplicationError> {
+    let value = match req.headers().get(&SYNC_HEADER_NAME) {
+        Some(v) => v,
+        None => return Ok(None),
+    };
+    let value = value
+        .to_str()
+        .map_err(|e| ApplicationError::BadRequest(format!("sync header: {e}")))?;
+    serde_json::from_str(value)
+        .map(Some)
+        .map_err(|e| ApplicationError::BadRequest(format!("sync header: {e}")))
+}
+
+/// build the sync request from header and body,either the header-and-stream
+/// protocol of newer clients or the multipart one of older clients.
+///
+/// bodies of uploads are spooled to disk if spool is given.
+async fn decode_sync_request(
+    req: &mut ServiceRequest,
+    spool: Option<&SpoolTarget>,
+) -> Result<(SyncRequest<Vec<u8>>, Option<SpooledBody>), ApplicationError> {
+    let pl = req.take_payload();
+    // behind a trusted reverse proxy this is the ip it forwarded for
+    let ip = match proxy::client_ip(req.request()) {
+        Some(ip) => ip,
+        None => {
+            // i.e. connections over a unix socket
+            log::debug!("no peer address,using unspecified ip");
+            IpAddr::V4(Ipv4Addr::UNSPECIFIED)
+        }
+    };
+    match sync_header(req)? {
+        Some(sync_header) => from_header_and_stream::<Vec<u8>>(sync_header, pl, ip, spool).await,
+        None => {
+            let bodyless_begin = req.method() == Method::GET && req.path().starts_with("/msync");
+            let pl = actix_multipart::Multipart::new(req.headers(), pl);
+            from_multipart::<Vec<u8>>(ip, pl, spool, bodyless_begin).await
+        }
     }
 }
 
@@ -130,7 +243,7 @@ where
     // S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error>,
     S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
     S::Future: 'static,
-    B: 'static,
+    B: MessageBody + 'static,
 {
     type Response = ServiceResponse<B>;
     type Error = Error;
@@ -147,45 +260,98 @@ where
     fn call(&self, mut req: ServiceRequest) -> Self::Future {
         let service = self.service.clone();
         Box::pin(async move {
-            // let r:anki::sync::media::begin::SyncBeginQuery=serde_json::from_str( req.query_string()).unwrap();
-            // let headers = req.headers();
-            let pl = req.take_payload();
-            // let (req,pl)=req.into_parts();
-            let headers = req.headers();
-            let ip = req.peer_addr();
-            let ip: Option<std::net::IpAddr> = match ip {
-                Some(s) => Some(s.ip()),
-                None => {
-                    log::error!("unable to get ip");
-                    None
-                }
+            let endpoint = if req.path().starts_with("/msync") {
+                "msync"
+            } else {
+                "sync"
             };
-            // construct struct SyncHeader.
-            let sync_header_value =
-                headers.get(&anki::sync::request::header_and_stream::SYNC_HEADER_NAME);
-            // let pl = req.take_payload();
-            let sync_request = match sync_header_value {
-                Some(sync_headers) => {
-                    // If SYNC_HEADER_NAME is present,
-                    // need to check if it is a str
-                    let sync_header: Option<anki::sync::request::header_and_stream::SyncHeader> =
-                        serde_json::from_str(sync_headers.to_str().ok().unwrap())
-                            .ok()
-                            .unwrap();
-                    // let pl = req.take_payload();
-
-                    from_header_and_stream::<Vec<u8>>(sync_header.unwrap(), pl, ip.unwrap()).await
-                }
-                None => {
-                    // let pl = req.take_payload();
-                    // If SYNC_HEADER_NAME is absent,
-                    let pl = actix_multipart::Multipart::new(headers, pl);
-
-                    from_multipart::<Vec<u8>>(ip.unwrap(), pl).await
-                }
+            let method = req
+                .match_info()
+                .get("method")
+                .unwrap_or("begin")
+                .to_string();
+            let spool = is_spooled(endpoint, &method).then(|| SpoolTarget {
+                auth_db: req
+                    .app_data::<web::Data<String>>()
+                    .map(|d| d.get_ref().clone()),
+                base_folder: req
+                    .app_data::<web::Data<PathBuf>>()
+                    .map(|d| d.get_ref().clone()),
+                max_payload_bytes: max_payload_bytes(
+                    req.app_data::<web::Data<Config>>()
+                        .map(|c| c.max_sync_payload_megs())
+                        .unwrap_or_else(|| ConfigLimits::default().max_sync_payload_megs),
+                ),
+            });
+            let (sync_request, spooled) = decode_sync_request(&mut req, spool.as_ref()).await?;
+            let wire_bytes = req
+                .headers()
+                .get(CONTENT_LENGTH)
+                .and_then(|v| v.to_str().ok())
+                .and_then(|v| v.parse().ok());
+            logging::set_sync_method(&method, sync_request.sync_version.0);
+            let decoded_bytes = match &spooled {
+                Some(body) => body.size() as usize,
+                None => sync_request.data.len(),
+            };
+            // uploads are handed to the handler on disk
+            if let Some(body) = spooled {
+                req.extensions_mut().insert(body);
+            }
+            let client_version = if sync_request.client_version.is_empty() {
+                sync_request
+                    .media_client_version
+                    .clone()
+                    .unwrap_or_default()
+            } else {
+                sync_request.client_version.clone()
             };
+            let client_ip = sync_request.ip;
+            let host_key = sync_request.sync_key.clone();
             req.extensions_mut().insert(sync_request);
             let res = service.call(req).await?;
+            metrics::observe_request(
+                endpoint,
+                &method,
+                res.status().as_u16(),
+                wire_bytes,
+                decoded_bytes,
+            );
+            // requests not authenticated for a user are not part of a session
+            let username = res.request().extensions().get::<SyncUser>().cloned();
+            let server_db = res
+                .request()
+                .app_data::<web::Data<Config>>()
+                .map(|c| c.server_db_path());
+            if let (Some(SyncUser(username)), Some(server_db)) = (username, server_db) {
+                // zstd responses are streamed,their size is sent along as a header
+                let bytes_out = match res.response().body().size() {
+                    BodySize::Sized(n) => n,
+                    _ => res
+                        .headers()
+                        .get(&ORIGINAL_SIZE)
+                        .and_then(|v| v.to_str().ok())
+                        .and_then(|v| v.parse().ok())
+                        .unwrap_or_default(),
+                };
+                let event = SyncEvent {
+                    username,
+                    host_key,
+                    endpoint,
+                    method,
+                    client_version,
+                    ip: client_ip,
+                    bytes_in: wire_bytes.unwrap_or(decoded_bytes as u64),
+                    bytes_out,
+                    error: res.response().error().map(|e| e.to_string()),
+                };
+                let logged = web::block(move || record_sync_event(server_db, &event)).await;
+                match logged {
+                    Ok(Err(e)) => log::error!("failed to write sync log: {e}"),
+                    Err(e) => log::error!("failed to write sync log: {e}"),
+                    Ok(Ok(())) => {}
+                }
+            }
             Ok(res)
         })
     }
@@ -195,7 +361,7 @@ pub struct SyncRequestWrapper;
 impl<S: 'static, B> Transform<S, ServiceRequest> for SyncRequestWrapper
 where
     S::Future: 'static,
-    B: 'static,
+    B: MessageBody + 'static,
     S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error>,
 {
     type Response = ServiceResponse<B>;
@@ -211,39 +377,62 @@ where
     }
 }
 /// return `hostkey` as response data if user authenticates successfully.
-/// `hoskey` is the username digest generated on the server.
+/// `hoskey` is a random token issued by the server for this login.
 ///
 /// clients just send username and password when logging in to the server.
-/// the server uees them to compute the sha256-hash which is the so-called
-/// `hostkey`.It is s process that is called `authentication`.compare these
-/// two hash to check whether they are equal pr mot,if so authentication
-/// succeed.Abd sends the host key back to the client.
+/// the server verifies the password against the hash stored in auth db,It is s
+/// process that is called `authentication`.if it succeeds,a new host key is
+/// recorded in table `hostkeys` and sent back to the client.
+///
+/// legacy sha256 hashes are replaced by an Argon2id hash on a successful login,
+/// through snapshot so that the user is not taken for re-passworded.
 pub async fn host_key(
     hkreq: HostKeyRequest,
-    users: &HashMap<String, User>,
+    client_version: &str,
+    ip: IpAddr,
+    auth_db: &str,
+    hashing: &ConfigHashing,
+    security: &ConfigSecurity,
+    snapshot: &AuthSnapshot,
 ) -> Result<HostKeyResponse, ApplicationError> {
     let username = hkreq.username;
     let password = hkreq.password;
-    // extract hash from User if username match,else return no such username error,
-    // let state = server.state.lock().expect("lock mytex");
-    let user = users.iter().find(|(_hash, u)| u.name == username);
-    match user {
-        Some((hash, _u)) => {
-            let actual_hash = compute_hash(&username, &password, hash);
-            if actual_hash == *hash {
-                Ok(HostKeyResponse {
-                    key: hash.to_string(),
-                })
-            } else {
-                Err(
-                    UserError::Authentication(format!("Authentication failed for user {username}"))
-                        .into(),
-                )
+    logging::set_username(&username);
+    check_login(security, &username, ip, auth_db)?;
+    let verified = match fetch_hash(auth_db, &username)? {
+        Some(hash) => {
+            let (name, pass) = (username.clone(), password.clone());
+            off_worker(move || verify_password(&name, &pass, &hash).then_some(hash)).await?
+        }
+        None => None,
+    };
+    if let Err(e) = record_login(security, &username, ip, verified.is_some(), auth_db) {
+        log::error!("failed to record login of user {username}: {e}");
+    }
+    let hash = match verified {
+        Some(hash) => hash,
+        None => {
+            metrics::auth_failed();
+            return Err(UserError::Authentication(format!(
+                "Authentication failed for user {username}"
+            ))
+            .into());
+        }
+    };
+    if is_legacy_hash(&hash) {
+        // migrate the legacy hash,keep on using it if that fails
+        let hashing = hashing.clone();
+        let migrated = match off_worker(move || create_pass_hash(&password, &hashing)).await? {
+            Ok(new_hash) => {
+                snapshot.replace_hash(&username, || store_hash(&username, new_hash, auth_db))
             }
+            Err(e) => Err(e),
+        };
+        match migrated {
+            Ok(_) => log::info!("migrated password hash of user {username} to argon2id"),
+            Err(e) => log::error!("failed to migrate password hash of user {username}: {e}"),
         }
-        None => Err(UserError::Authentication(format!(
-            "Authentication failed for nonexistent user {username}"
-        ))
-        .into()),
     }
+    let key = issue_host_key(&username, client_version, ip, auth_db)?;
+    Ok(HostKeyResponse { key })
 }
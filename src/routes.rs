@@ -1,12 +1,26 @@
-#![allow(clippy::await_holding_lock)]
-use crate::app_config::set_users;
-use crate::db::fetch_users;
+use crate::app_config::{reconcile_users, AuthSnapshot};
+use crate::backup::{backup_after_sync, backup_before_upload};
+use crate::collection::receive_upload;
+use crate::config::Config;
+use crate::hostkey::{record_device_activity, username_for_host_key};
+use crate::logging;
+use crate::maintenance::check_maintenance;
+use crate::media::{upload_changes, SplitUpload};
+use crate::media_store::MediaStore;
+use crate::metrics;
+use crate::proxy;
+use crate::quota::{check_collection_upload, check_media_upload};
 use crate::response::make_response;
+use crate::server::{run_blocking, SyncServer};
+use crate::shutdown::Shutdown;
+use crate::spool::SpooledBody;
+use crate::storage::{self, Area};
+use crate::sync_log::SyncUser;
 
 use crate::{error::ApplicationError, request};
 use actix_web::http::StatusCode;
 use actix_web::web;
-use actix_web::{error, HttpResponse};
+use actix_web::{error, HttpMessage, HttpRequest, HttpResponse};
 use anki::sync::collection::protocol::SyncMethod;
 use anki::sync::collection::protocol::SyncProtocol;
 use anki::sync::http_server::SimpleServer;
@@ -19,15 +33,20 @@ use anki::sync::request::IntoSyncRequest;
 use anki::sync::request::SyncRequest;
 use anki::sync::version::SyncVersion;
 
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 
 // here the syncrequest may fail,need be constructed from query
 // older clients such as Android 2.16 alpha will use this method
 pub async fn media_begin_get(
+    http_req: HttpRequest,
     query: web::Query<SyncBeginQuery>,
-    server: web::Data<Arc<SimpleServer>>,
+    server: web::Data<Arc<SyncServer>>,
+    auth_db: web::Data<String>,
+    base_folder: web::Data<PathBuf>,
+    shutdown: web::Data<Shutdown>,
 ) -> actix_web::Result<HttpResponse> {
+    shutdown.reject_new_session()?;
     let query = query.into_inner();
     let host_key = query.host_key;
 
@@ -39,8 +58,14 @@ pub async fn media_begin_get(
 
     req.sync_key = host_key;
     req.sync_version = SyncVersion::multipart();
+    if let Some(ip) = proxy::client_ip(&http_req) {
+        req.ip = ip;
+    }
 
     let mut req: SyncRequest<Vec<u8>> = req.into_output_type();
+    let host_key = authenticate(&http_req, &mut req, &auth_db)?;
+    check_maintenance(&server, &base_folder.join(&req.sync_key), &req.sync_key)?;
+    let user = server.user(&req.sync_key)?;
 
     // clone of media_begin_post
     if let Some(ver) = &req.media_client_version {
@@ -51,7 +76,7 @@ pub async fn media_begin_get(
             ApplicationError::InternalServerError("serialize begin request".to_string())
         })?;
     }
-    begin_wrapper(req.into_output_type(), server).await
+    begin_wrapper(req.into_output_type(), user, host_key).await
 }
 
 /// newer clients such 2.1.57 use post method.  
@@ -63,11 +88,19 @@ pub async fn media_begin_get(
 /// we take the method begin from the media_sync_handler and use it in media_begin_post and
 /// media_begin_get
 pub async fn media_begin_post(
+    http_req: HttpRequest,
     req: Option<web::ReqData<SyncRequest<Vec<u8>>>>,
-    server: web::Data<Arc<SimpleServer>>,
+    server: web::Data<Arc<SyncServer>>,
+    auth_db: web::Data<String>,
+    base_folder: web::Data<PathBuf>,
+    shutdown: web::Data<Shutdown>,
 ) -> actix_web::Result<HttpResponse> {
+    shutdown.reject_new_session()?;
     // argument req should safe to unwrap
     let mut req = req.unwrap().into_inner();
+    let host_key = authenticate(&http_req, &mut req, &auth_db)?;
+    check_maintenance(&server, &base_folder.join(&req.sync_key), &req.sync_key)?;
+    let user = server.user(&req.sync_key)?;
     if let Some(ver) = &req.media_client_version {
         req.data = serde_json::to_vec(&SyncBeginRequest {
             client_version: ver.clone(),
@@ -77,143 +110,302 @@ pub async fn media_begin_post(
         })?;
     }
 
-    begin_wrapper(req.into_output_type(), server).await
+    begin_wrapper(req.into_output_type(), user, host_key).await
+}
+
+/// replace the host key sent by the client with the internal key the user is
+/// stored under in `SimpleServerInner.users`,and return the original host key.
+///
+/// unknown and revoked host keys are rejected with 403.the username is also
+/// stored in the extensions of http_req for the sync log.
+fn authenticate<T>(
+    http_req: &HttpRequest,
+    req: &mut SyncRequest<T>,
+    auth_db: &str,
+) -> Result<String, ApplicationError> {
+    let username = match username_for_host_key(&req.sync_key, auth_db)? {
+        Some(username) => username,
+        None => {
+            return Err(ApplicationError::InvalidHostKey(
+                "unknown or revoked host key".to_string(),
+            ))
+        }
+    };
+    logging::set_username(&username);
+    let client_version = client_version(req);
+    if let Err(e) = record_device_activity(&req.sync_key, client_version, req.ip, auth_db) {
+        log::error!("failed to record device activity of user {username}: {e}");
+    }
+    http_req.extensions_mut().insert(SyncUser(username.clone()));
+    Ok(std::mem::replace(&mut req.sync_key, username))
+}
+
+/// client version of the request,older clients only send it on media begin.
+fn client_version<T>(req: &SyncRequest<T>) -> &str {
+    if req.client_version.is_empty() {
+        req.media_client_version.as_deref().unwrap_or_default()
+    } else {
+        &req.client_version
+    }
+}
+
+/// media begin answers with the key of the request as session key,which is
+/// the internal one by now,so give the client back its own host key.
+fn restore_host_key(data: Vec<u8>, internal_key: &str, host_key: &str) -> Vec<u8> {
+    let mut value: serde_json::Value = match serde_json::from_slice(&data) {
+        Ok(v) => v,
+        Err(_) => return data,
+    };
+    let wrapped = value.get("data").map_or(false, |d| d.is_object());
+    let fields = if wrapped {
+        value["data"].as_object_mut()
+    } else {
+        value.as_object_mut()
+    };
+    if let Some(fields) = fields {
+        for v in fields.values_mut() {
+            if v.as_str() == Some(internal_key) {
+                *v = host_key.into();
+            }
+        }
+    }
+    match serde_json::to_vec(&value) {
+        Ok(v) => v,
+        Err(_) => data,
+    }
 }
 
 /// a wrapper for the media function begin.  
 async fn begin_wrapper(
     req: SyncRequest<Vec<u8>>,
-    server: web::Data<Arc<SimpleServer>>,
+    user: Arc<SimpleServer>,
+    host_key: String,
 ) -> actix_web::Result<HttpResponse> {
     let sync_version = req.sync_version;
-    let data = server
-        // .lock()
-        // .expect("server call method")
-        .begin(req.into_output_type())
-        .await
+    let internal_key = req.sync_key.clone();
+    let data = run_blocking(async move { user.begin(req.into_output_type()).await })
+        .await?
         .map_err(|e| ApplicationError::InternalServerError(e.to_string()))?
         .data;
+    let data = restore_host_key(data, &internal_key, &host_key);
     Ok(make_response(data, sync_version))
 }
 
 pub async fn media_sync_handler(
+    http_req: HttpRequest,
     req: Option<web::ReqData<SyncRequest<Vec<u8>>>>,
     method: web::Path<MediaSyncMethod>, //(endpoint,sync_method)
-    server: web::Data<Arc<SimpleServer>>,
+    server: web::Data<Arc<SyncServer>>,
+    auth_db: web::Data<String>,
+    base_folder: web::Data<PathBuf>,
+    shutdown: web::Data<Shutdown>,
 ) -> actix_web::Result<HttpResponse> {
     let sync_method = method.into_inner();
+    if matches!(sync_method, MediaSyncMethod::Begin) {
+        shutdown.reject_new_session()?;
+    }
 
-    let req = req.unwrap().into_inner();
+    let mut req = req.unwrap().into_inner();
+    let host_key = authenticate(&http_req, &mut req, &auth_db)?;
+    check_maintenance(&server, &base_folder.join(&req.sync_key), &req.sync_key)?;
+    let user = server.user(&req.sync_key)?;
     let sync_version = req.sync_version;
     match sync_method {
         MediaSyncMethod::Begin => {
+            let internal_key = req.sync_key.clone();
             // As begin and meta are two functions that are called rirst,so we do the error handling here.
-            let data = server
-                .begin(req.into_output_type())
-                .await
+            let data = run_blocking(async move { user.begin(req.into_output_type()).await })
+                .await?
                 .map_err(|e| match e.code {
                     StatusCode::FORBIDDEN => ApplicationError::InvalidHostKey(e.context),
                     _ => ApplicationError::InternalServerError(e.context),
                 })?
                 .data;
+            let data = restore_host_key(data, &internal_key, &host_key);
             Ok(make_response(data, sync_version))
         }
         MediaSyncMethod::MediaChanges => {
-            let data = server
-                // .lock()
-                // .expect("server call method")
-                .media_changes(req.into_output_type())
-                .await
-                .map_err(|e| ApplicationError::InternalServerError(e.to_string()))?
-                .data;
+            let data =
+                run_blocking(async move { user.media_changes(req.into_output_type()).await })
+                    .await?
+                    .map_err(|e| ApplicationError::InternalServerError(e.to_string()))?
+                    .data;
             Ok(make_response(data, sync_version))
         }
         MediaSyncMethod::UploadChanges => {
-            let data = server
-                // .lock()
-                // .expect("server call method")
-                .upload_changes(req)
-                .await
-                .map_err(|e| ApplicationError::InternalServerError(e.to_string()))?
-                .data;
+            // spooled to disk by the middleware
+            let upload = http_req
+                .extensions_mut()
+                .remove::<SpooledBody>()
+                .ok_or_else(|| ApplicationError::BadRequest("upload body missing".to_string()))?;
+            let user_folder = base_folder.join(&req.sync_key);
+            let auth_db = auth_db.get_ref().clone();
+            let storage = server.storage();
+            let store = http_req.app_data::<web::Data<MediaStore>>().cloned();
+            let username = req.sync_key.clone();
+            let data = run_blocking(async move {
+                check_media_upload(&auth_db, &username, &user_folder, upload.path())?;
+                let changes = match storage.is_working_copy(Area::Media) && store.is_none() {
+                    true => vec![],
+                    false => upload_changes(upload.path())?,
+                };
+                if let Some(store) = &store {
+                    store.unshare(&user_folder, &changes)?;
+                }
+                let mut answer = None;
+                let mut applied = 0;
+                let mut result = Ok(());
+                for zip in SplitUpload::open(upload.path())? {
+                    let mut part = req.clone();
+                    part.data = zip?;
+                    match user.upload_changes(part).await {
+                        Ok(res) => {
+                            answer = Some(merge_upload_answer(answer, res.data));
+                            applied += 1;
+                        }
+                        Err(e) => {
+                            result = Err(ApplicationError::InternalServerError(e.to_string()));
+                            break;
+                        }
+                    }
+                }
+                // changes anki applied before failing are kept
+                let applied = &changes[..applied.min(changes.len())];
+                storage::push_media_changes(storage.as_ref(), &username, &user_folder, applied)?;
+                if let Some(store) = &store {
+                    store.apply_changes(&username, &user_folder, applied)?;
+                }
+                result?;
+                answer.ok_or_else(|| {
+                    ApplicationError::InternalServerError("empty media upload".to_string())
+                })
+            })
+            .await??;
             Ok(make_response(data, sync_version))
         }
         MediaSyncMethod::DownloadFiles => {
-            let data = server
-                // .lock()
-                // .expect("server call method")
-                .download_files(req.into_output_type())
-                .await
-                .map_err(|e| ApplicationError::InternalServerError(e.to_string()))?
-                .data;
+            let user_folder = base_folder.join(&req.sync_key);
+            let storage = server.storage();
+            let data = run_blocking(async move {
+                let fetched =
+                    storage::fetch_media(storage.as_ref(), &req.sync_key, &user_folder, &req.data)?;
+                let data = user.download_files(req.into_output_type()).await;
+                storage::evict_media(&fetched);
+                Ok::<_, ApplicationError>(data)
+            })
+            .await??
+            .map_err(|e| ApplicationError::InternalServerError(e.to_string()))?
+            .data;
             Ok(make_response(data, sync_version))
         }
         MediaSyncMethod::MediaSanity => {
-            let data = server
-                // .lock()
-                // .expect("server call method")
-                .media_sanity_check(req.into_output_type())
-                .await
-                .map_err(|e| ApplicationError::InternalServerError(e.to_string()))?
-                .data;
+            let data =
+                run_blocking(async move { user.media_sanity_check(req.into_output_type()).await })
+                    .await?
+                    .map_err(|e| ApplicationError::InternalServerError(e.to_string()))?
+                    .data;
             Ok(make_response(data, sync_version))
         }
     }
 }
 
+/// fold the answer of anki to one of the zips a media upload was split into
+/// into the answer to the whole upload,`{"data":[processed,usn],"err":""}`:
+/// the files processed add up,the usn is that of the last zip.
+fn merge_upload_answer(merged: Option<Vec<u8>>, answer: Vec<u8>) -> Vec<u8> {
+    let processed = |data: &serde_json::Value| data["data"][0].as_u64();
+    let merged = match merged.and_then(|m| serde_json::from_slice(&m).ok()) {
+        Some(merged) => merged,
+        None => return answer,
+    };
+    let mut value: serde_json::Value = match serde_json::from_slice(&answer) {
+        Ok(value) => value,
+        Err(_) => return answer,
+    };
+    if let (Some(before), Some(now)) = (processed(&merged), processed(&value)) {
+        value["data"][0] = (before + now).into();
+    }
+    serde_json::to_vec(&value).unwrap_or(answer)
+}
+
+/// login,the only method not authenticated by a host key.
+///
+/// access user database when client request login and bring in-memory
+/// accounts in line with it (new,deleted and re-passworded users)
+async fn host_key_login(
+    req: SyncRequest<Vec<u8>>,
+    server: &SyncServer,
+    auth_db: &str,
+    base_folder: &Path,
+    config: &Config,
+    snapshot: &AuthSnapshot,
+) -> Result<Vec<u8>, ApplicationError> {
+    reconcile_users(server, snapshot, base_folder, auth_db)?;
+    let client_version = client_version(&req).to_string();
+    let ip = req.ip;
+    let hkreq: HostKeyRequest = req
+        .into_output_type()
+        .json()
+        .map_err(ApplicationError::HttpError)?;
+    let data = request::host_key(
+        hkreq,
+        &client_version,
+        ip,
+        auth_db,
+        config.hashing(),
+        config.security(),
+        snapshot,
+    )
+    .await?;
+    Ok(serde_json::to_vec(&data)?)
+}
+
+// every argument is an extractor of actix-web
+#[allow(clippy::too_many_arguments)]
 pub async fn collecction_sync_handler(
+    http_req: HttpRequest,
     req: Option<web::ReqData<SyncRequest<Vec<u8>>>>,
     method: web::Path<SyncMethod>, //(endpoint,sync_method)
-    server: web::Data<Arc<SimpleServer>>,
+    server: web::Data<Arc<SyncServer>>,
     auth_db: web::Data<String>,
     base_folder: web::Data<PathBuf>,
+    config: web::Data<Config>,
+    snapshot: web::Data<AuthSnapshot>,
+    shutdown: web::Data<Shutdown>,
 ) -> actix_web::Result<HttpResponse> {
     let sync_method = method.into_inner();
+    // sessions in progress may go on while the server is shutting down
+    if matches!(
+        sync_method,
+        SyncMethod::HostKey
+            | SyncMethod::Meta
+            | SyncMethod::Start
+            | SyncMethod::Upload
+            | SyncMethod::Download
+    ) {
+        shutdown.reject_new_session()?;
+    }
     // let sync_method:SyncMethod=serde_json::from_str(&method.into_inner().0).unwrap();
     //  let o= req.0.into_output_type();
-    let req = req.unwrap().into_inner();
+    let mut req = req.unwrap().into_inner();
     let sync_version = req.sync_version;
+    if matches!(sync_method, SyncMethod::HostKey) {
+        let data = host_key_login(req, &server, &auth_db, &base_folder, &config, &snapshot).await?;
+        return Ok(make_response(data, sync_version));
+    }
+    authenticate(&http_req, &mut req, &auth_db)?;
+    check_maintenance(&server, &base_folder.join(&req.sync_key), &req.sync_key)?;
+    let user = server.user(&req.sync_key)?;
     // have to convert from anki response types to actix-web response type,in sync/response
     // TODO:And response from sync procedures must be processed by make_response
     // take out vec<u8> from json
     let res = match sync_method {
-        SyncMethod::HostKey => {
-            // dynamically add users，access user database when client request login and
-            // update in-memory account,only add new accounts
-            let auth_db = auth_db.as_str();
-            let users = fetch_users(auth_db).map_err(ApplicationError::Sqlite)?;
-            let mut state = server.state.lock().expect("msg");
-            if let Some(u) = users {
-                let in_memory_hostkeys = state.users.keys().collect::<Vec<_>>();
-                // compare host_key,filter new hostkey
-                let mut new_users = vec![];
-                for (name, hash) in u {
-                    if !in_memory_hostkeys.contains(&&hash) {
-                        new_users.push((name, hash));
-                    }
-                }
-                let u = set_users(base_folder.into_inner().as_path(), new_users)?;
-                for (k, v) in u {
-                    state.users.insert(k, v);
-                }
-            }
-            //  should replace the official host key function with the existing one.
-            // in this case server is not consumed abd nay block later methods.
-            let hkreq: HostKeyRequest = req
-                .into_output_type()
-                .json()
-                .map_err(ApplicationError::HttpError)?;
-            let usrs = &state.users;
-            let data = request::host_key(hkreq, usrs).await?;
-            let data = serde_json::to_vec(&data)?;
-            make_response(data, sync_version)
-        }
+        SyncMethod::HostKey => unreachable!("login is handled above"),
         SyncMethod::Meta => {
             // As begin and meta are two functions that are called rirst after authentication,
             // so we do the error handling here.
-            let data = server
-                .meta(req.into_output_type())
-                .await
+            let data = run_blocking(async move { user.meta(req.into_output_type()).await })
+                .await?
                 .map_err(|e| match e.code {
                     StatusCode::FORBIDDEN => ApplicationError::InvalidHostKey(e.context),
                     _ => ApplicationError::InternalServerError(e.context),
@@ -222,102 +414,101 @@ pub async fn collecction_sync_handler(
             make_response(data, sync_version)
         }
         SyncMethod::Start => {
-            let data = server
-                // .lock()
-                // .expect("server call method")
-                .start(req.into_output_type())
-                .await
+            metrics::session_started(&req.sync_key);
+            let data = run_blocking(async move { user.start(req.into_output_type()).await })
+                .await?
                 .map_err(|e| ApplicationError::InternalServerError(e.to_string()))?
                 .data;
             make_response(data, sync_version)
         }
         SyncMethod::ApplyGraves => {
-            let data = server
-                // .lock()
-                // .expect("server call method")
-                .apply_graves(req.into_output_type())
-                .await
+            let data = run_blocking(async move { user.apply_graves(req.into_output_type()).await })
+                .await?
                 .map_err(|e| ApplicationError::InternalServerError(e.to_string()))?
                 .data;
             make_response(data, sync_version)
         }
         SyncMethod::ApplyChanges => {
-            let data = server
-                // .lock()
-                // .expect("server call method")
-                .apply_changes(req.into_output_type())
-                .await
-                .map_err(|e| ApplicationError::InternalServerError(e.to_string()))?
-                .data;
+            let data =
+                run_blocking(async move { user.apply_changes(req.into_output_type()).await })
+                    .await?
+                    .map_err(|e| ApplicationError::InternalServerError(e.to_string()))?
+                    .data;
             make_response(data, sync_version)
         }
         SyncMethod::Chunk => {
-            let data = server
-                // .lock()
-                // .expect("server call method")
-                .chunk(req.into_output_type())
-                .await
+            let data = run_blocking(async move { user.chunk(req.into_output_type()).await })
+                .await?
                 .map_err(|e| ApplicationError::InternalServerError(e.to_string()))?
                 .data;
             make_response(data, sync_version)
         }
         SyncMethod::ApplyChunk => {
-            let data = server
-                // .lock()
-                // .expect("server call method")
-                .apply_chunk(req.into_output_type())
-                .await
+            let data = run_blocking(async move { user.apply_chunk(req.into_output_type()).await })
+                .await?
                 .map_err(|e| ApplicationError::InternalServerError(e.to_string()))?
                 .data;
             make_response(data, sync_version)
         }
         SyncMethod::SanityCheck2 => {
-            let data = server
-                // .lock()
-                // .expect("server call method")
-                .sanity_check(req.into_output_type())
-                .await
+            let data = run_blocking(async move { user.sanity_check(req.into_output_type()).await })
+                .await?
                 .map_err(|e| ApplicationError::InternalServerError(e.to_string()))?
                 .data;
             make_response(data, sync_version)
         }
         SyncMethod::Finish => {
-            let data = server
-                // .lock()
-                // .expect("server call method")
-                .finish(req.into_output_type())
-                .await
+            let username = req.sync_key.clone();
+            let data = run_blocking(async move { user.finish(req.into_output_type()).await })
+                .await?
                 .map_err(|e| ApplicationError::InternalServerError(e.to_string()))?
                 .data;
+            metrics::session_ended(&username, "finish");
+            backup_after_sync(
+                &config,
+                server.get_ref().clone(),
+                base_folder.join(&username),
+                &username,
+            );
             make_response(data, sync_version)
         }
         SyncMethod::Abort => {
-            let data = server
-                // .lock()
-                // .expect("server call method")
-                .abort(req.into_output_type())
-                .await
+            metrics::session_ended(&req.sync_key, "abort");
+            let data = run_blocking(async move { user.abort(req.into_output_type()).await })
+                .await?
                 .map_err(|e| ApplicationError::InternalServerError(e.to_string()))?
                 .data;
             make_response(data, sync_version)
         }
         SyncMethod::Upload => {
-            let data = server
-                // .lock()
-                // .expect("server call method")
-                .upload(req.into_output_type())
-                .await
-                .map_err(|e| ApplicationError::InternalServerError(e.to_string()))?
-                .data;
-
+            // spooled to disk by the middleware
+            let upload = http_req
+                .extensions_mut()
+                .remove::<SpooledBody>()
+                .ok_or_else(|| ApplicationError::BadRequest("upload body missing".to_string()))?;
+            check_collection_upload(&auth_db, &req.sync_key, upload.size())?;
+            let user_folder = base_folder.join(&req.sync_key);
+            backup_before_upload(
+                &config,
+                server.get_ref().clone(),
+                user_folder.clone(),
+                &req.sync_key,
+            )
+            .await?;
+            metrics::full_sync("upload");
+            let sync_server = server.get_ref().clone();
+            let username = req.sync_key.clone();
+            let data = run_blocking(async move {
+                sync_server.with_user(&username, |user| receive_upload(user, upload))
+            })
+            .await?
+            .ok_or_else(|| ApplicationError::InvalidHostKey("user is not loaded".to_string()))??;
             make_response(data, sync_version)
         }
         SyncMethod::Download => {
-            let data = server
-                // .lock()
-                // .expect("server call method")
-                .download(req.into_output_type())
-                .await
+            metrics::full_sync("download");
+            let data = run_blocking(async move { user.download(req.into_output_type()).await })
+                .await?
                 .map_err(|e| ApplicationError::InternalServerError(e.to_string()))?
                 .data;
             make_response(data, sync_version)
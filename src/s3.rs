@@ -0,0 +1,503 @@
+//! media files in S3-compatible object storage.
+//!
+//! Requests are signed with AWS signature version 4 and sent with a blocking
+//! http client,like all storage operations they run on the blocking thread
+//! pool.Only media files are kept in the bucket,collections,media databases
+//! and backups stay in the local storage,so the media database of each user
+//! keeps tracking its media locally.Objects are put with their sha1 checksum,
+//! which S3 keeps and returns on `HEAD`,so that `media gc` can check them
+//! without downloading them.
+use crate::config::ConfigS3;
+use crate::error::ApplicationError;
+use crate::storage::{Area, LocalStorage, Storage, StoredObject};
+use base64::{engine::general_purpose::STANDARD, Engine as _};
+use hmac::{Hmac, Mac};
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+use std::io::{self, Read};
+use std::path::Path;
+use std::time::Duration;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// how long to wait for a connection and for a whole request
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// characters left as they are in uri components,the unreserved ones of
+/// RFC 3986 as signature version 4 requires
+fn is_unreserved(b: u8) -> bool {
+    b.is_ascii_alphanumeric() || b"-_.~".contains(&b)
+}
+
+/// percent-encode s,keeping `/` if it is a path
+fn uri_encode(s: &str, path: bool) -> String {
+    let mut encoded = String::with_capacity(s.len());
+    for b in s.bytes() {
+        if is_unreserved(b) || (path && b == b'/') {
+            encoded.push(b as char);
+        } else {
+            encoded.push_str(&format!("%{b:02X}"));
+        }
+    }
+    encoded
+}
+
+fn hmac(key: &[u8], data: &str) -> Vec<u8> {
+    let mut mac = HmacSha256::new_from_slice(key).expect("hmac accepts keys of any size");
+    mac.update(data.as_bytes());
+    mac.finalize().into_bytes().to_vec()
+}
+
+fn sha256_hex(data: &[u8]) -> String {
+    hex::encode(Sha256::digest(data))
+}
+
+/// query string in canonical form,encoded and sorted by name
+fn canonical_query(query: &[(&str, &str)]) -> String {
+    let mut query: Vec<(String, String)> = query
+        .iter()
+        .map(|(k, v)| (uri_encode(k, false), uri_encode(v, false)))
+        .collect();
+    query.sort();
+    query
+        .iter()
+        .map(|(k, v)| format!("{k}={v}"))
+        .collect::<Vec<_>>()
+        .join("&")
+}
+
+/// names of the signed headers as listed in the canonical request
+fn signed_headers(headers: &[(&str, &str)]) -> String {
+    headers
+        .iter()
+        .map(|(k, _)| *k)
+        .collect::<Vec<_>>()
+        .join(";")
+}
+
+/// canonical form of a request,headers are the signed ones as (name,value)
+/// pairs sorted by name,names in lower case
+fn canonical_request(
+    method: &str,
+    path: &str,
+    query: &str,
+    headers: &[(&str, &str)],
+    payload_hash: &str,
+) -> String {
+    let canonical_headers: String = headers.iter().map(|(k, v)| format!("{k}:{v}\n")).collect();
+    format!(
+        "{method}\n{path}\n{query}\n{canonical_headers}\n{}\n{payload_hash}",
+        signed_headers(headers)
+    )
+}
+
+/// credential scope of a request to service sent at amz_date
+/// (`%Y%m%dT%H%M%SZ`)
+fn scope(amz_date: &str, region: &str, service: &str) -> String {
+    format!("{}/{region}/{service}/aws4_request", &amz_date[..8])
+}
+
+/// signature version 4 of a canonical request,the signing key is derived
+/// from the secret key by each part of the scope in turn
+fn signature(secret_key: &str, amz_date: &str, scope: &str, canonical_request: &str) -> String {
+    let string_to_sign = format!(
+        "AWS4-HMAC-SHA256\n{amz_date}\n{scope}\n{}",
+        sha256_hex(canonical_request.as_bytes())
+    );
+    let signing_key = scope
+        .split('/')
+        .fold(format!("AWS4{secret_key}").into_bytes(), |key, part| {
+            hmac(&key, part)
+        });
+    hex::encode(hmac(&signing_key, &string_to_sign))
+}
+
+/// undo the escaping of xml text
+fn unescape(s: &str) -> String {
+    s.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&")
+}
+
+/// text of the elements named tag in xml,in order
+fn elements<'a>(xml: &'a str, tag: &str) -> Vec<&'a str> {
+    let open = format!("<{tag}>");
+    let close = format!("</{tag}>");
+    let mut found = vec![];
+    let mut rest = xml;
+    while let Some(start) = rest.find(&open) {
+        rest = &rest[start + open.len()..];
+        match rest.find(&close) {
+            Some(end) => {
+                found.push(&rest[..end]);
+                rest = &rest[end + close.len()..];
+            }
+            None => break,
+        }
+    }
+    found
+}
+
+/// error for a response with an unexpected status,with the message S3 sent
+fn status_error(context: &str, code: u16, response: ureq::Response) -> io::Error {
+    let body = response.into_string().unwrap_or_default();
+    let reason = elements(&body, "Message").first().map(|m| unescape(m));
+    io::Error::new(
+        io::ErrorKind::Other,
+        format!("{context}: status {code} {}", reason.unwrap_or_default()),
+    )
+}
+
+/// a bucket of an S3-compatible server
+struct Bucket {
+    agent: ureq::Agent,
+    /// `http` or `https`
+    scheme: String,
+    /// host and port of the endpoint
+    host: String,
+    bucket: String,
+    prefix: String,
+    region: String,
+    access_key: String,
+    secret_key: String,
+    path_style: bool,
+}
+
+impl Bucket {
+    fn new(config: &ConfigS3) -> Result<Self, ApplicationError> {
+        let invalid = |m: &str| ApplicationError::ParseConfig(format!("storage.media: {m}"));
+        let (scheme, rest) = config
+            .endpoint
+            .split_once("://")
+            .ok_or_else(|| invalid("endpoint must start with http:// or https://"))?;
+        if scheme != "http" && scheme != "https" {
+            return Err(invalid("endpoint must start with http:// or https://"));
+        }
+        let host = rest.trim_end_matches('/');
+        // the http client leaves default ports out of the host header it sends
+        let host = match scheme {
+            "http" => host.strip_suffix(":80"),
+            _ => host.strip_suffix(":443"),
+        }
+        .unwrap_or(host);
+        if host.is_empty() || host.contains('/') {
+            return Err(invalid("endpoint must not have a path"));
+        }
+        if config.bucket.is_empty() {
+            return Err(invalid("bucket is not set"));
+        }
+        let from_env = |value: &str, var: &str| match value {
+            "" => std::env::var(var).unwrap_or_default(),
+            v => v.to_string(),
+        };
+        let access_key = from_env(&config.access_key, "AWS_ACCESS_KEY_ID");
+        let secret_key = from_env(&config.secret_key, "AWS_SECRET_ACCESS_KEY");
+        if access_key.is_empty() || secret_key.is_empty() {
+            return Err(invalid("access_key and secret_key are not set"));
+        }
+        let prefix = match config.prefix.trim_matches('/') {
+            "" => String::new(),
+            p => format!("{p}/"),
+        };
+        Ok(Bucket {
+            agent: ureq::AgentBuilder::new()
+                .timeout_connect(CONNECT_TIMEOUT)
+                .timeout(REQUEST_TIMEOUT)
+                .build(),
+            scheme: scheme.to_string(),
+            host: host.to_string(),
+            bucket: config.bucket.clone(),
+            prefix,
+            region: config.region.clone(),
+            access_key,
+            secret_key,
+            path_style: config.path_style,
+        })
+    }
+
+    /// key of the object name of user
+    fn key(&self, username: &str, name: &str) -> String {
+        format!("{}{username}/{name}", self.prefix)
+    }
+
+    /// host and encoded path of key,the bucket itself if key is empty
+    fn location(&self, key: &str) -> (String, String) {
+        let key = uri_encode(key, true);
+        match (self.path_style, key.is_empty()) {
+            (true, true) => (self.host.clone(), format!("/{}", self.bucket)),
+            (true, false) => (self.host.clone(), format!("/{}/{key}", self.bucket)),
+            (false, _) => (format!("{}.{}", self.bucket, self.host), format!("/{key}")),
+        }
+    }
+
+    /// send a signed request,query and headers given as (name,value) pairs,
+    /// header names in lower case.Return the status along with the response,
+    /// errors are those of the connection.
+    fn send(
+        &self,
+        method: &str,
+        key: &str,
+        query: &[(&str, &str)],
+        headers: &[(&str, &str)],
+        body: &[u8],
+    ) -> io::Result<(u16, ureq::Response)> {
+        let (host, path) = self.location(key);
+        let query = canonical_query(query);
+        let amz_date = chrono::Utc::now().format("%Y%m%dT%H%M%SZ").to_string();
+        let payload_hash = sha256_hex(body);
+        let mut signed: Vec<(&str, &str)> = vec![
+            ("host", &host),
+            ("x-amz-content-sha256", &payload_hash),
+            ("x-amz-date", &amz_date),
+        ];
+        signed.extend_from_slice(headers);
+        signed.sort();
+        let canonical_request = canonical_request(method, &path, &query, &signed, &payload_hash);
+        let scope = scope(&amz_date, &self.region, "s3");
+        let authorization = format!(
+            "AWS4-HMAC-SHA256 Credential={}/{scope}, SignedHeaders={}, Signature={}",
+            self.access_key,
+            signed_headers(&signed),
+            signature(&self.secret_key, &amz_date, &scope, &canonical_request)
+        );
+
+        let mut url = format!("{}://{host}{path}", self.scheme);
+        if !query.is_empty() {
+            url.push('?');
+            url.push_str(&query);
+        }
+        let mut request = self.agent.request(method, &url);
+        for (name, value) in signed.iter().filter(|(k, _)| *k != "host") {
+            request = request.set(name, value);
+        }
+        let result = request
+            .set("authorization", &authorization)
+            .send_bytes(body);
+        match result {
+            Ok(response) => Ok((response.status(), response)),
+            Err(ureq::Error::Status(code, response)) => Ok((code, response)),
+            Err(e) => Err(io::Error::new(
+                io::ErrorKind::Other,
+                format!("{method} {url}: {e}"),
+            )),
+        }
+    }
+
+    fn get(&self, key: &str) -> io::Result<Option<Vec<u8>>> {
+        match self.send("GET", key, &[], &[], &[])? {
+            (200, response) => {
+                let mut data = vec![];
+                response.into_reader().read_to_end(&mut data)?;
+                Ok(Some(data))
+            }
+            (404, _) => Ok(None),
+            (code, response) => Err(status_error(&format!("get {key}"), code, response)),
+        }
+    }
+
+    fn put(&self, key: &str, data: &[u8]) -> io::Result<()> {
+        let sha1 = STANDARD.encode(Sha1::digest(data));
+        match self.send("PUT", key, &[], &[("x-amz-checksum-sha1", &sha1)], data)? {
+            (200, _) => Ok(()),
+            (code, response) => Err(status_error(&format!("put {key}"), code, response)),
+        }
+    }
+
+    fn delete(&self, key: &str) -> io::Result<()> {
+        match self.send("DELETE", key, &[], &[], &[])? {
+            (200 | 204 | 404, _) => Ok(()),
+            (code, response) => Err(status_error(&format!("delete {key}"), code, response)),
+        }
+    }
+
+    /// sha1 checksum S3 keeps for the object,hex-encoded,`None` for objects
+    /// put without one (i.e. by an older version) and missing objects
+    fn sha1(&self, key: &str) -> io::Result<Option<String>> {
+        let mode = [("x-amz-checksum-mode", "ENABLED")];
+        match self.send("HEAD", key, &[], &mode, &[])? {
+            (200, response) => Ok(response
+                .header("x-amz-checksum-sha1")
+                .and_then(|c| STANDARD.decode(c).ok())
+                .filter(|c| c.len() == 20)
+                .map(hex::encode)),
+            (404, _) => Ok(None),
+            (code, response) => Err(status_error(&format!("head {key}"), code, response)),
+        }
+    }
+
+    /// objects whose keys start with prefix,named without it
+    fn list(&self, prefix: &str) -> io::Result<Vec<StoredObject>> {
+        let mut objects = vec![];
+        let mut token = None;
+        loop {
+            let mut query = vec![("list-type", "2"), ("prefix", prefix)];
+            if let Some(t) = &token {
+                query.push(("continuation-token", t.as_str()));
+            }
+            let response = match self.send("GET", "", &query, &[], &[])? {
+                (200, response) => response,
+                (code, response) => {
+                    return Err(status_error(&format!("list {prefix}"), code, response))
+                }
+            };
+            let xml = response.into_string()?;
+            for contents in elements(&xml, "Contents") {
+                let key = match elements(contents, "Key").first() {
+                    Some(k) => unescape(k),
+                    None => continue,
+                };
+                let size = elements(contents, "Size")
+                    .first()
+                    .and_then(|s| s.parse().ok())
+                    .unwrap_or_default();
+                if let Some(name) = key.strip_prefix(prefix) {
+                    objects.push(StoredObject {
+                        name: name.to_string(),
+                        size,
+                        modified: None,
+                    });
+                }
+            }
+            token = elements(&xml, "NextContinuationToken")
+                .first()
+                .map(|t| unescape(t));
+            if token.is_none() {
+                return Ok(objects);
+            }
+        }
+    }
+}
+
+/// media files in a bucket,everything else in the local storage
+pub struct S3MediaStorage {
+    bucket: Bucket,
+    local: LocalStorage,
+}
+
+impl S3MediaStorage {
+    pub fn new(config: &ConfigS3, local: LocalStorage) -> Result<Self, ApplicationError> {
+        Ok(S3MediaStorage {
+            bucket: Bucket::new(config)?,
+            local,
+        })
+    }
+}
+
+impl Storage for S3MediaStorage {
+    fn kind(&self) -> &'static str {
+        "s3 media"
+    }
+
+    fn is_working_copy(&self, area: Area) -> bool {
+        area != Area::Media
+    }
+
+    fn get(&self, area: Area, username: &str, name: &str) -> io::Result<Option<Vec<u8>>> {
+        match area {
+            Area::Media => self.bucket.get(&self.bucket.key(username, name)),
+            _ => self.local.get(area, username, name),
+        }
+    }
+
+    fn put(&self, area: Area, username: &str, name: &str, data: &[u8]) -> io::Result<()> {
+        match area {
+            Area::Media => self.bucket.put(&self.bucket.key(username, name), data),
+            _ => self.local.put(area, username, name, data),
+        }
+    }
+
+    fn delete(&self, area: Area, username: &str, name: &str) -> io::Result<()> {
+        match area {
+            Area::Media => self.bucket.delete(&self.bucket.key(username, name)),
+            _ => self.local.delete(area, username, name),
+        }
+    }
+
+    fn list(&self, area: Area, username: &str) -> io::Result<Vec<StoredObject>> {
+        match area {
+            Area::Media => self.bucket.list(&self.bucket.key(username, "")),
+            _ => self.local.list(area, username),
+        }
+    }
+
+    fn recorded_sha1(&self, area: Area, username: &str, name: &str) -> io::Result<Option<String>> {
+        match area {
+            Area::Media => self.bucket.sha1(&self.bucket.key(username, name)),
+            _ => self.local.recorded_sha1(area, username, name),
+        }
+    }
+
+    fn put_file(&self, area: Area, username: &str, name: &str, src: &Path) -> io::Result<()> {
+        match area {
+            Area::Media => self.put(area, username, name, &std::fs::read(src)?),
+            _ => self.local.put_file(area, username, name, src),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // the get-vanilla and get-vanilla-query-order-key-case cases of the
+    // signature version 4 test suite published by AWS
+    const SECRET_KEY: &str = "wJalrXUtnFEMI/K7MDENG+bPxRfiCYEXAMPLEKEY";
+    const AMZ_DATE: &str = "20150830T123600Z";
+    const HEADERS: [(&str, &str); 2] =
+        [("host", "example.amazonaws.com"), ("x-amz-date", AMZ_DATE)];
+
+    fn sign(query: &str) -> (String, String) {
+        let request = canonical_request("GET", "/", query, &HEADERS, &sha256_hex(b""));
+        let scope = scope(AMZ_DATE, "us-east-1", "service");
+        let signature = signature(SECRET_KEY, AMZ_DATE, &scope, &request);
+        (request, signature)
+    }
+
+    #[test]
+    fn signs_get_vanilla() {
+        let (request, signature) = sign("");
+        assert_eq!(
+            request,
+            "GET\n/\n\nhost:example.amazonaws.com\nx-amz-date:20150830T123600Z\n\nhost;x-amz-date\ne3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+        assert_eq!(
+            sha256_hex(request.as_bytes()),
+            "bb579772317eb040ac9ed261061d46c1f17a8133879d6129b6e1c25292927e63"
+        );
+        assert_eq!(
+            signature,
+            "5fa00fa31553b73ebf1942676e86291e8372ff2a2260956d9b8aae1d763fbf31"
+        );
+    }
+
+    #[test]
+    fn signs_sorted_query() {
+        let query = canonical_query(&[("Param2", "value2"), ("Param1", "value1")]);
+        assert_eq!(query, "Param1=value1&Param2=value2");
+        assert_eq!(
+            sign(&query).1,
+            "b97d918cfa904a5beff61c982a1b6f458b799221646efd99d3219ec94cdf2500"
+        );
+    }
+
+    #[test]
+    fn encodes_keys() {
+        assert_eq!(uri_encode("u/a b+ü.mp3", true), "u/a%20b%2B%C3%BC.mp3");
+        assert_eq!(uri_encode("u/a~b", false), "u%2Fa~b");
+    }
+
+    #[test]
+    fn parses_list_objects() {
+        let xml = "<ListBucketResult><Contents><Key>m/a&amp;b.jpg</Key><Size>3</Size></Contents>\
+            <Contents><Key>m/c.jpg</Key><Size>5</Size></Contents>\
+            <NextContinuationToken>t&lt;1</NextContinuationToken></ListBucketResult>";
+        let contents = elements(xml, "Contents");
+        assert_eq!(contents.len(), 2);
+        assert_eq!(unescape(elements(contents[0], "Key")[0]), "m/a&b.jpg");
+        assert_eq!(elements(contents[1], "Size"), ["5"]);
+        assert_eq!(unescape(elements(xml, "NextContinuationToken")[0]), "t<1");
+    }
+}
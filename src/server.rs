@@ -0,0 +1,184 @@
+//! the in-memory users of the running server.
+//!
+//! Every user is served by a `SimpleServer` of its own that holds just this
+//! user,so the mutex inside it only serializes the requests of one user and
+//! different users sync in parallel.The map handing out these per-user servers
+//! is only locked while looking one up.Sync operations run on the blocking
+//! thread pool through `run_blocking`,they never stall the http workers.
+use crate::error::ApplicationError;
+use crate::logging;
+use crate::storage::SharedStorage;
+use actix_web::web;
+use anki::sync::http_server::user::User;
+use anki::sync::http_server::{SimpleServer, SimpleServerInner};
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::{Arc, Mutex, RwLock, TryLockError};
+
+pub struct SyncServer {
+    users: RwLock<HashMap<String, Arc<SimpleServer>>>,
+    storage: SharedStorage,
+}
+
+/// close the collection of a user and drop any sync in progress.
+pub(crate) fn close_user_collection(user: &mut User) {
+    user.sync_state = None;
+    if let Some(col) = user.col.take() {
+        if let Err(e) = col.close(None) {
+            log::error!("failed to close collection of user {}: {e}", user.name);
+        }
+    }
+}
+
+/// run f on the single user of a per-user server
+fn with_user<R>(server: &SimpleServer, f: impl FnOnce(&mut User) -> R) -> Option<R> {
+    let mut state = server.state.lock().expect("lock user state");
+    state.users.values_mut().next().map(f)
+}
+
+impl SyncServer {
+    /// a server without users keeping their data in storage
+    pub fn new(storage: SharedStorage) -> Self {
+        SyncServer {
+            users: RwLock::default(),
+            storage,
+        }
+    }
+
+    pub fn storage(&self) -> SharedStorage {
+        self.storage.clone()
+    }
+
+    /// the server of the user a request was authenticated for
+    pub fn user(&self, username: &str) -> Result<Arc<SimpleServer>, ApplicationError> {
+        self.users
+            .read()
+            .expect("lock users")
+            .get(username)
+            .cloned()
+            .ok_or_else(|| {
+                ApplicationError::InvalidHostKey(format!("user {username} is not loaded"))
+            })
+    }
+
+    pub fn usernames(&self) -> Vec<String> {
+        self.users
+            .read()
+            .expect("lock users")
+            .keys()
+            .cloned()
+            .collect()
+    }
+
+    pub fn contains(&self, username: &str) -> bool {
+        self.users
+            .read()
+            .expect("lock users")
+            .contains_key(username)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.users.read().expect("lock users").is_empty()
+    }
+
+    /// add a user,it is stored under its name which serves as its sync key
+    pub fn insert(&self, user: User) {
+        let name = user.name.clone();
+        let server = SimpleServer {
+            state: Mutex::new(SimpleServerInner {
+                users: HashMap::from([(name.clone(), user)]),
+            }),
+        };
+        self.users
+            .write()
+            .expect("lock users")
+            .insert(name, Arc::new(server));
+    }
+
+    /// unload a user after closing its collection,return whether it was loaded.
+    pub fn remove(&self, username: &str) -> bool {
+        let server = self.users.write().expect("lock users").remove(username);
+        match server {
+            Some(s) => {
+                with_user(&s, close_user_collection);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// run f on the in-memory user,`None` if it is not loaded.
+    ///
+    /// this waits for the request of the user being served,if any.
+    pub fn with_user<R>(&self, username: &str, f: impl FnOnce(&mut User) -> R) -> Option<R> {
+        let server = self
+            .users
+            .read()
+            .expect("lock users")
+            .get(username)
+            .cloned()?;
+        with_user(&server, f)
+    }
+
+    /// number of users with a collection sync started but not yet finished or
+    /// aborted,users busy serving a request are counted as well.
+    pub fn sessions_in_progress(&self) -> usize {
+        let servers: Vec<_> = self
+            .users
+            .read()
+            .expect("lock users")
+            .values()
+            .cloned()
+            .collect();
+        servers
+            .iter()
+            .filter(|s| match s.state.try_lock() {
+                Ok(state) => state.users.values().any(|u| u.sync_state.is_some()),
+                Err(TryLockError::WouldBlock) => true,
+                Err(TryLockError::Poisoned(_)) => false,
+            })
+            .count()
+    }
+
+    /// close every open collection and unload all users along with their media
+    /// databases.
+    pub fn close_all(&self) {
+        let servers: Vec<_> = self
+            .users
+            .write()
+            .expect("lock users")
+            .drain()
+            .map(|(_, s)| s)
+            .collect();
+        for server in servers {
+            with_user(&server, close_user_collection);
+        }
+    }
+}
+
+/// run blocking work such as sqlite queries or password hashing on the
+/// blocking thread pool instead of an http worker.
+pub async fn off_worker<R: Send + 'static>(
+    f: impl FnOnce() -> R + Send + 'static,
+) -> Result<R, ApplicationError> {
+    let context = logging::current();
+    web::block(move || logging::with_context(context, f))
+        .await
+        .map_err(|e| ApplicationError::InternalServerError(e.to_string()))
+}
+
+/// run a sync operation on the blocking thread pool.
+///
+/// the operations of anki do their collection and media work synchronously
+/// while holding the lock of the user,which must not happen on an http worker.
+pub async fn run_blocking<F>(op: F) -> Result<F::Output, ApplicationError>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    // log lines of the operation belong to the request being handled
+    let context = logging::current();
+    web::block(move || logging::with_context(context, || async_std::task::block_on(op)))
+        .await
+        .map_err(|e| ApplicationError::InternalServerError(e.to_string()))
+}
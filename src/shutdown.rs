@@ -0,0 +1,83 @@
+//! graceful shutdown on SIGTERM and SIGINT.
+//!
+//! On a signal the server stops accepting new sync sessions (they are answered
+//! with 503) and waits for the sessions in progress to finish or abort,at most
+//! for the grace period set in section `[shutdown]`,before stopping the http
+//! server.Collections and media databases are closed once it has stopped.
+use crate::error::ApplicationError;
+use crate::server::SyncServer;
+use actix_web::dev::ServerHandle;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// interval at which sessions in progress are counted while draining
+const DRAIN_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// whether the server is shutting down,shared with the request handlers
+#[derive(Default)]
+pub struct Shutdown {
+    draining: AtomicBool,
+}
+
+impl Shutdown {
+    /// refuse to start a new sync session once shutdown has begun.
+    pub fn reject_new_session(&self) -> Result<(), ApplicationError> {
+        if self.draining.load(Ordering::SeqCst) {
+            return Err(ApplicationError::ServiceUnavailable(
+                "The server is shutting down,please try again later".to_string(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(unix)]
+async fn wait_for_signal() {
+    use actix_web::rt::signal::unix::{signal, SignalKind};
+    use futures_util::future::{select, FutureExt};
+    let mut terminate = match signal(SignalKind::terminate()) {
+        Ok(s) => s,
+        Err(e) => {
+            log::error!("unable to listen for SIGTERM: {e}");
+            let _ = actix_web::rt::signal::ctrl_c().await;
+            return;
+        }
+    };
+    let interrupt = actix_web::rt::signal::ctrl_c().boxed_local();
+    select(interrupt, terminate.recv().boxed_local()).await;
+}
+
+#[cfg(not(unix))]
+async fn wait_for_signal() {
+    let _ = actix_web::rt::signal::ctrl_c().await;
+}
+
+/// stop the http server gracefully once SIGTERM or SIGINT is received.
+///
+/// the server must have been built with `disable_signals()`.
+pub fn spawn_shutdown_handler(
+    handle: ServerHandle,
+    server: Arc<SyncServer>,
+    shutdown: Arc<Shutdown>,
+    grace_period: Duration,
+) {
+    actix_web::rt::spawn(async move {
+        wait_for_signal().await;
+        log::info!("shutting down,new sync sessions are refused");
+        shutdown.draining.store(true, Ordering::SeqCst);
+        let deadline = Instant::now() + grace_period;
+        loop {
+            let sessions = server.sessions_in_progress();
+            if sessions == 0 {
+                break;
+            }
+            if Instant::now() >= deadline {
+                log::warn!("{sessions} sync sessions still in progress,shutting down anyway");
+                break;
+            }
+            actix_web::rt::time::sleep(DRAIN_POLL_INTERVAL).await;
+        }
+        handle.stop(true).await;
+    });
+}
@@ -0,0 +1,233 @@
+//! spooling of upload bodies to disk.
+//!
+//! Full collection uploads and media uploads are decompressed chunk by chunk
+//! into a temp file in the folder of the uploading user instead of being
+//! buffered in memory,and the payload size limit is checked on every chunk so
+//! an oversized upload is refused as soon as it crosses the limit.The limit
+//! is `limits.max_sync_payload_megs` for the body as sent,three times as much
+//! once decompressed,like anki does.
+use crate::error::ApplicationError;
+use crate::hostkey::username_for_host_key;
+use crate::server::off_worker;
+use bytes::Bytes;
+use futures_util::{Stream, StreamExt};
+use std::fmt::Display;
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use tempfile::NamedTempFile;
+
+/// prefix of spooled uploads,leftovers of a crash may be deleted
+pub const SPOOL_PREFIX: &str = ".upload-";
+
+/// delete uploads left behind in folder by a crash
+pub fn remove_leftovers(folder: &Path) -> io::Result<()> {
+    for entry in std::fs::read_dir(folder)? {
+        let entry = entry?;
+        if entry
+            .file_name()
+            .to_string_lossy()
+            .starts_with(SPOOL_PREFIX)
+        {
+            std::fs::remove_file(entry.path())?;
+        }
+    }
+    Ok(())
+}
+
+/// whether the body of method is spooled to disk
+pub fn is_spooled(endpoint: &str, method: &str) -> bool {
+    matches!(
+        (endpoint, method),
+        ("sync", "upload") | ("msync", "uploadChanges")
+    )
+}
+
+/// size limit of request bodies as sent,in bytes,for a limit in megabytes
+pub fn max_payload_bytes(megs: u64) -> u64 {
+    megs * 1024 * 1024
+}
+
+fn too_large(limit: u64) -> ApplicationError {
+    ApplicationError::PayloadTooLarge(format!("upload exceeds {} MB", limit / 1024 / 1024))
+}
+
+/// where the body of an upload is spooled to
+pub struct SpoolTarget {
+    /// auth db and data root,to find the folder of the user
+    pub auth_db: Option<String>,
+    pub base_folder: Option<PathBuf>,
+    /// size limit of the body as sent,in bytes
+    pub max_payload_bytes: u64,
+}
+
+impl SpoolTarget {
+    /// the folder of the user holding host_key,uploads of unknown host keys are
+    /// refused before their body is read.Without data root the temp dir is used.
+    pub async fn folder(&self, host_key: &str) -> Result<PathBuf, ApplicationError> {
+        let (auth_db, base_folder) = match (&self.auth_db, &self.base_folder) {
+            (Some(a), Some(b)) => (a.clone(), b),
+            _ => return Ok(std::env::temp_dir()),
+        };
+        let host_key = host_key.to_owned();
+        match off_worker(move || username_for_host_key(&host_key, &auth_db)).await? {
+            Ok(Some(username)) => Ok(base_folder.join(username)),
+            Ok(None) => Err(ApplicationError::InvalidHostKey(
+                "unknown or revoked host key".to_string(),
+            )),
+            Err(e) => Err(ApplicationError::InternalServerError(e.to_string())),
+        }
+    }
+}
+
+/// an upload body written to a temp file,deleted when dropped unless persisted
+#[derive(Debug)]
+pub struct SpooledBody {
+    file: NamedTempFile,
+    len: u64,
+}
+
+impl SpooledBody {
+    /// size of the decompressed body
+    pub fn size(&self) -> u64 {
+        self.len
+    }
+
+    pub fn path(&self) -> &Path {
+        self.file.path()
+    }
+
+    /// move the body to path,atomically as it is on the same file system.
+    pub fn persist(self, path: &Path) -> io::Result<()> {
+        self.file.persist(path).map(|_| ()).map_err(|e| e.error)
+    }
+}
+
+/// temp file refusing to grow past a limit
+struct LimitedFile {
+    file: NamedTempFile,
+    written: u64,
+    limit: u64,
+    // limit of the body as sent,reported when refusing it
+    payload_limit: u64,
+    exceeded: bool,
+    // error of the file itself,as opposed to one of the decoder writing to it
+    io_error: bool,
+}
+
+impl LimitedFile {
+    fn new(folder: &Path, limit: u64, payload_limit: u64) -> io::Result<Self> {
+        Ok(LimitedFile {
+            file: tempfile::Builder::new()
+                .prefix(SPOOL_PREFIX)
+                .tempfile_in(folder)?,
+            written: 0,
+            limit,
+            payload_limit,
+            exceeded: false,
+            io_error: false,
+        })
+    }
+
+    /// map an error that occurred while writing to self
+    fn error(&self, e: io::Error) -> ApplicationError {
+        if self.exceeded {
+            too_large(self.payload_limit)
+        } else if self.io_error {
+            ApplicationError::IO(e)
+        } else {
+            ApplicationError::BadRequest(format!("upload could not be decompressed: {e}"))
+        }
+    }
+
+    fn finish(mut self) -> Result<SpooledBody, ApplicationError> {
+        self.file.flush()?;
+        Ok(SpooledBody {
+            file: self.file,
+            len: self.written,
+        })
+    }
+}
+
+impl Write for LimitedFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.written + buf.len() as u64 > self.limit {
+            self.exceeded = true;
+            return Err(io::Error::new(io::ErrorKind::Other, "payload too large"));
+        }
+        let n = self.file.write(buf).map_err(|e| {
+            self.io_error = true;
+            e
+        })?;
+        self.written += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+/// spool a zstd-compressed body,as sent by clients using the sync header.
+pub async fn spool_zstd<S, E>(
+    mut body: S,
+    folder: &Path,
+    limit: u64,
+) -> Result<SpooledBody, ApplicationError>
+where
+    S: Stream<Item = Result<Bytes, E>> + Unpin,
+    E: Display,
+{
+    let mut decoder =
+        zstd::stream::write::Decoder::new(LimitedFile::new(folder, limit * 3, limit)?)?;
+    let mut received = 0;
+    while let Some(chunk) = body.next().await {
+        let chunk = chunk.map_err(|e| ApplicationError::BadRequest(format!("upload: {e}")))?;
+        received += chunk.len() as u64;
+        if received > limit {
+            return Err(too_large(limit));
+        }
+        if let Err(e) = decoder.write_all(&chunk) {
+            return Err(decoder.get_ref().error(e));
+        }
+    }
+    if let Err(e) = decoder.flush() {
+        return Err(decoder.get_ref().error(e));
+    }
+    decoder.into_inner().finish()
+}
+
+/// spool the data field of a multipart body as it is.
+pub async fn spool_field<S, E>(
+    mut field: S,
+    folder: &Path,
+    limit: u64,
+) -> Result<SpooledBody, ApplicationError>
+where
+    S: Stream<Item = Result<Bytes, E>> + Unpin,
+    E: Display,
+{
+    let mut file = LimitedFile::new(folder, limit, limit)?;
+    while let Some(chunk) = field.next().await {
+        let chunk = chunk.map_err(|e| ApplicationError::BadRequest(format!("upload: {e}")))?;
+        if let Err(e) = file.write_all(&chunk) {
+            return Err(file.error(e));
+        }
+    }
+    file.finish()
+}
+
+/// decompress a gzipped spooled body into a new one next to it.
+pub fn gunzip(spooled: SpooledBody, limit: u64) -> Result<SpooledBody, ApplicationError> {
+    let folder = spooled
+        .path()
+        .parent()
+        .map(Path::to_path_buf)
+        .unwrap_or_else(std::env::temp_dir);
+    let mut out = LimitedFile::new(&folder, limit * 3, limit)?;
+    let mut decoder = flate2::read::GzDecoder::new(File::open(spooled.path())?);
+    if let Err(e) = io::copy(&mut decoder, &mut out) {
+        return Err(out.error(e));
+    }
+    out.finish()
+}
@@ -0,0 +1,293 @@
+//! where collections,media files and backups of users are kept.
+//!
+//! anki works on the files in the folder of each user on local disk,the
+//! collection,the media database and the media folder.A `Storage` holds media
+//! files and backups,objects named per `Area` and user.The local storage keeps
+//! them right in the user folders (and backups under `<root>/backups`),so the
+//! working copy is the stored one and nothing has to be copied.Media files in
+//! another backend are copied into the media folder when they are asked for
+//! and out of it after every change.Collections and media databases always
+//! stay in the user folders.
+//!
+//! The backend is chosen in section `[storage]`.
+use crate::config::Config;
+use crate::error::ApplicationError;
+use crate::media::media_folder;
+use serde::Deserialize;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::SystemTime;
+
+/// the kind of data an object belongs to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Area {
+    /// media files,named as in the media database
+    Media,
+    /// compressed collection backups
+    Backup,
+}
+
+/// an object as listed by a storage
+#[derive(Debug, Clone)]
+pub struct StoredObject {
+    pub name: String,
+    /// size in bytes
+    pub size: u64,
+    pub modified: Option<SystemTime>,
+}
+
+/// a place to keep the objects of users,see the module docs.
+///
+/// the methods block,call them on the blocking thread pool.
+pub trait Storage: Send + Sync {
+    /// name of the backend in config and logs,i.e. `local`
+    fn kind(&self) -> &'static str;
+
+    /// whether the objects of area are the files in the user folder anki works
+    /// on,as opposed to copies that have to be fetched and pushed
+    fn is_working_copy(&self, area: Area) -> bool;
+
+    /// content of an object,`None` if there is no such object
+    fn get(&self, area: Area, username: &str, name: &str) -> io::Result<Option<Vec<u8>>>;
+
+    fn put(&self, area: Area, username: &str, name: &str, data: &[u8]) -> io::Result<()>;
+
+    fn delete(&self, area: Area, username: &str, name: &str) -> io::Result<()>;
+
+    /// the objects of user in area
+    fn list(&self, area: Area, username: &str) -> io::Result<Vec<StoredObject>>;
+
+    /// sha1 the backend keeps along with an object,hex-encoded,so that its
+    /// content can be checked without fetching it.`None` if it keeps none.
+    fn recorded_sha1(
+        &self,
+        _area: Area,
+        _username: &str,
+        _name: &str,
+    ) -> io::Result<Option<String>> {
+        Ok(None)
+    }
+
+    /// store the file at src as an object,src may be moved away
+    fn put_file(&self, area: Area, username: &str, name: &str, src: &Path) -> io::Result<()> {
+        self.put(area, username, name, &fs::read(src)?)
+    }
+
+    /// copy an object to dst,return false if there is no such object
+    fn get_file(&self, area: Area, username: &str, name: &str, dst: &Path) -> io::Result<bool> {
+        match self.get(area, username, name)? {
+            Some(data) => {
+                write_atomically(dst, &data)?;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+}
+
+pub type SharedStorage = Arc<dyn Storage>;
+
+/// write data to path through a temp file next to it
+fn write_atomically(path: &Path, data: &[u8]) -> io::Result<()> {
+    let folder = path.parent().unwrap_or_else(|| Path::new("."));
+    fs::create_dir_all(folder)?;
+    let mut file = tempfile::NamedTempFile::new_in(folder)?;
+    io::Write::write_all(&mut file, data)?;
+    file.persist(path).map(|_| ()).map_err(|e| e.error)
+}
+
+/// objects kept as files,collections and media in the user folders under the
+/// data root and backups under the backup root
+pub struct LocalStorage {
+    data_root: PathBuf,
+    backup_root: PathBuf,
+}
+
+impl LocalStorage {
+    pub fn new(data_root: impl Into<PathBuf>, backup_root: impl Into<PathBuf>) -> Self {
+        LocalStorage {
+            data_root: data_root.into(),
+            backup_root: backup_root.into(),
+        }
+    }
+
+    fn folder(&self, area: Area, username: &str) -> PathBuf {
+        match area {
+            Area::Media => media_folder(&self.data_root.join(username)),
+            Area::Backup => self.backup_root.join(username),
+        }
+    }
+
+    fn path(&self, area: Area, username: &str, name: &str) -> io::Result<PathBuf> {
+        // names come from clients for media,they must not leave the folder
+        if name.is_empty() || name.contains(['/', '\\']) || name == "." || name == ".." {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid object name {name:?}"),
+            ));
+        }
+        Ok(self.folder(area, username).join(name))
+    }
+}
+
+impl Storage for LocalStorage {
+    fn kind(&self) -> &'static str {
+        "local"
+    }
+
+    fn is_working_copy(&self, _area: Area) -> bool {
+        true
+    }
+
+    fn get(&self, area: Area, username: &str, name: &str) -> io::Result<Option<Vec<u8>>> {
+        match fs::read(self.path(area, username, name)?) {
+            Ok(data) => Ok(Some(data)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn put(&self, area: Area, username: &str, name: &str, data: &[u8]) -> io::Result<()> {
+        write_atomically(&self.path(area, username, name)?, data)
+    }
+
+    fn delete(&self, area: Area, username: &str, name: &str) -> io::Result<()> {
+        match fs::remove_file(self.path(area, username, name)?) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+
+    fn list(&self, area: Area, username: &str) -> io::Result<Vec<StoredObject>> {
+        let folder = self.folder(area, username);
+        if !folder.exists() {
+            return Ok(vec![]);
+        }
+        let mut objects = vec![];
+        for entry in fs::read_dir(folder)? {
+            let entry = entry?;
+            let metadata = entry.metadata()?;
+            if !metadata.is_file() {
+                continue;
+            }
+            objects.push(StoredObject {
+                name: entry.file_name().to_string_lossy().to_string(),
+                size: metadata.len(),
+                modified: metadata.modified().ok(),
+            });
+        }
+        Ok(objects)
+    }
+
+    fn put_file(&self, area: Area, username: &str, name: &str, src: &Path) -> io::Result<()> {
+        let path = self.path(area, username, name)?;
+        if let Some(folder) = path.parent() {
+            fs::create_dir_all(folder)?;
+        }
+        // a rename fails across file systems
+        if fs::rename(src, &path).is_err() {
+            write_atomically(&path, &fs::read(src)?)?;
+            fs::remove_file(src)?;
+        }
+        Ok(())
+    }
+}
+
+/// the storage chosen in config
+pub fn from_config(config: &Config) -> Result<SharedStorage, ApplicationError> {
+    let storage = config.storage();
+    match storage.backend.as_str() {
+        "local" => {
+            let local = LocalStorage::new(config.data_root_path(), config.backup_root_path());
+            match &storage.media {
+                None => Ok(Arc::new(local)),
+                #[cfg(feature = "s3")]
+                Some(s3) => Ok(Arc::new(crate::s3::S3MediaStorage::new(s3, local)?)),
+                #[cfg(not(feature = "s3"))]
+                Some(_) => Err(ApplicationError::ParseConfig(
+                    "section [storage.media] is set but S3 support was not built in the binary"
+                        .into(),
+                )),
+            }
+        }
+        other => Err(ApplicationError::ParseConfig(format!(
+            "unknown storage backend {other}"
+        ))),
+    }
+}
+
+/// store the media files added by an `uploadChanges` zip and delete the
+/// removed ones,changes as returned by `media::upload_changes`.The local
+/// copies written by anki are deleted once stored.
+pub fn push_media_changes(
+    storage: &dyn Storage,
+    username: &str,
+    folder: &Path,
+    changes: &[(String, Option<String>)],
+) -> Result<(), ApplicationError> {
+    if storage.is_working_copy(Area::Media) {
+        return Ok(());
+    }
+    let media = media_folder(folder);
+    for (fname, entry) in changes {
+        let path = media.join(fname);
+        match entry {
+            Some(_) => {
+                storage.put(Area::Media, username, fname, &fs::read(&path)?)?;
+                fs::remove_file(&path)?;
+            }
+            None => storage.delete(Area::Media, username, fname)?,
+        }
+    }
+    Ok(())
+}
+
+/// body of a `downloadFiles` request
+#[derive(Deserialize)]
+struct DownloadFiles {
+    files: Vec<String>,
+}
+
+/// fetch the media files asked for by a `downloadFiles` request that are not
+/// in the media folder of the user,return the files fetched.
+///
+/// anki zips them from there,`evict_media` deletes them afterwards.
+pub fn fetch_media(
+    storage: &dyn Storage,
+    username: &str,
+    folder: &Path,
+    request: &[u8],
+) -> Result<Vec<PathBuf>, ApplicationError> {
+    if storage.is_working_copy(Area::Media) {
+        return Ok(vec![]);
+    }
+    let request: DownloadFiles = serde_json::from_slice(request)?;
+    let media = media_folder(folder);
+    let mut fetched = vec![];
+    for fname in request.files {
+        let path = media.join(&fname);
+        if path.exists() {
+            continue;
+        }
+        if storage.get_file(Area::Media, username, &fname, &path)? {
+            fetched.push(path);
+        } else {
+            log::warn!("media file {fname} of user {username} is missing in storage");
+        }
+    }
+    Ok(fetched)
+}
+
+/// delete media files fetched by `fetch_media`
+pub fn evict_media(fetched: &[PathBuf]) {
+    for path in fetched {
+        if let Err(e) = fs::remove_file(path) {
+            log::warn!(
+                "failed to delete fetched media file {}: {e}",
+                path.display()
+            );
+        }
+    }
+}
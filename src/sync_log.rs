@@ -0,0 +1,260 @@
+//! persistent history of the syncs of each user,kept in table `sync_log` of
+//! server db.
+//!
+//! A row is one sync session:a collection sync from `meta` to `finish` (or a
+//! full upload/download),or a media sync from `begin` to `mediaSanity`.Each
+//! request of the session appends its method to the row and updates the bytes
+//! transferred,the duration and the outcome of the last request.
+use crate::hostkey::unix_now;
+use crate::media_store;
+use chrono::{Local, TimeZone};
+use lazy_static::lazy_static;
+use rusqlite::Connection;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const CREATE_SYNC_LOG_TABLE: &str = "CREATE TABLE IF NOT EXISTS sync_log
+(id INTEGER PRIMARY KEY, username VARCHAR NOT NULL, kind VARCHAR NOT NULL,
+methods VARCHAR NOT NULL DEFAULT '', client_version VARCHAR NOT NULL DEFAULT '',
+ip VARCHAR NOT NULL DEFAULT '', started INTEGER NOT NULL, finished INTEGER NOT NULL,
+bytes_in INTEGER NOT NULL DEFAULT 0, bytes_out INTEGER NOT NULL DEFAULT 0,
+outcome VARCHAR NOT NULL DEFAULT 'ok', error VARCHAR)";
+const CREATE_SYNC_LOG_INDEX: &str =
+    "CREATE INDEX IF NOT EXISTS ix_sync_log_username ON sync_log (username, started)";
+
+/// sessions older than this are deleted when a new one is logged
+const RETENTION_DAYS: i64 = 365;
+
+lazy_static! {
+    // row of the session in progress,by (host key,endpoint) so that the syncs
+    // of several devices of a user are logged apart
+    static ref OPEN_SESSIONS: Mutex<HashMap<(String, String), i64>> = Mutex::new(HashMap::new());
+}
+
+/// username a sync request was authenticated for,stored in the request
+/// extensions by the handlers.
+#[derive(Clone)]
+pub struct SyncUser(pub String);
+
+/// a request of a sync session,as seen once its response is ready
+pub struct SyncEvent {
+    pub username: String,
+    pub host_key: String,
+    /// `sync` or `msync`
+    pub endpoint: &'static str,
+    pub method: String,
+    pub client_version: String,
+    pub ip: IpAddr,
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+    pub error: Option<String>,
+}
+
+/// a logged sync session
+pub struct SyncLogEntry {
+    pub kind: String,
+    pub methods: String,
+    pub client_version: String,
+    pub ip: String,
+    /// unix time in milliseconds
+    pub started: i64,
+    pub finished: i64,
+    pub bytes_in: i64,
+    pub bytes_out: i64,
+    pub outcome: String,
+    pub error: Option<String>,
+}
+
+fn now_millis() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as i64)
+        .unwrap_or_default()
+}
+
+/// create the tables of server db (`sync_log` and `media_refs`) if they do
+/// not exist yet.
+pub fn create_server_db<P: AsRef<Path>>(path: P) -> Result<(), rusqlite::Error> {
+    let conn = Connection::open(path)?;
+    conn.execute(CREATE_SYNC_LOG_TABLE, [])?;
+    conn.execute(CREATE_SYNC_LOG_INDEX, [])?;
+    conn.execute(media_store::CREATE_MEDIA_REFS_TABLE, [])?;
+    conn.execute(media_store::CREATE_MEDIA_REFS_INDEX, [])?;
+    conn.close().map_err(|(_, e)| e)?;
+    Ok(())
+}
+
+/// whether method begins a new session
+fn starts_session(endpoint: &str, method: &str) -> bool {
+    match endpoint {
+        "msync" => method == "begin",
+        _ => matches!(method, "meta" | "upload" | "download"),
+    }
+}
+
+/// whether method is the last of a session
+fn ends_session(endpoint: &str, method: &str) -> bool {
+    match endpoint {
+        "msync" => method == "mediaSanity",
+        _ => matches!(method, "finish" | "abort" | "upload" | "download"),
+    }
+}
+
+fn session_kind(endpoint: &str, method: &str) -> &'static str {
+    match (endpoint, method) {
+        ("msync", _) => "media",
+        (_, "upload") => "full_upload",
+        (_, "download") => "full_download",
+        _ => "normal",
+    }
+}
+
+/// log a request of a sync session.
+pub fn record_sync_event<P: AsRef<Path>>(
+    server_db: P,
+    event: &SyncEvent,
+) -> Result<(), rusqlite::Error> {
+    let key = (event.host_key.clone(), event.endpoint.to_string());
+    let open = if starts_session(event.endpoint, &event.method) {
+        None
+    } else {
+        let sessions = OPEN_SESSIONS.lock().expect("lock sync sessions");
+        sessions.get(&key).copied()
+    };
+    let conn = Connection::open(server_db)?;
+    let now = now_millis();
+    let id = match open {
+        Some(id) => id,
+        None => {
+            conn.execute(
+                "DELETE FROM sync_log WHERE started<?",
+                [(unix_now() - RETENTION_DAYS * 86400) * 1000],
+            )?;
+            conn.execute(
+                "INSERT INTO sync_log (username, kind, client_version, ip, started, finished)
+VALUES (?, ?, ?, ?, ?, ?)",
+                rusqlite::params![
+                    event.username,
+                    session_kind(event.endpoint, &event.method),
+                    event.client_version,
+                    event.ip.to_string(),
+                    now,
+                    now
+                ],
+            )?;
+            conn.last_insert_rowid()
+        }
+    };
+    let outcome = match (&event.error, event.method.as_str()) {
+        (Some(_), _) => "error",
+        (None, "abort") => "aborted",
+        _ => "ok",
+    };
+    conn.execute(
+        "UPDATE sync_log SET methods=methods || (CASE methods WHEN '' THEN '' ELSE ',' END) || ?,
+client_version=coalesce(nullif(?, ''), client_version), bytes_in=bytes_in+?,
+bytes_out=bytes_out+?, finished=?, outcome=?, error=coalesce(?, error) WHERE id=?",
+        rusqlite::params![
+            event.method,
+            event.client_version,
+            event.bytes_in as i64,
+            event.bytes_out as i64,
+            now,
+            outcome,
+            event.error,
+            id
+        ],
+    )?;
+    let mut sessions = OPEN_SESSIONS.lock().expect("lock sync sessions");
+    if ends_session(event.endpoint, &event.method) {
+        sessions.remove(&key);
+    } else {
+        sessions.insert(key, id);
+    }
+    Ok(())
+}
+
+/// the most recent sync sessions of user,newest first.
+pub fn sync_log<P: AsRef<Path>>(
+    server_db: P,
+    username: &str,
+    limit: usize,
+) -> Result<Vec<SyncLogEntry>, rusqlite::Error> {
+    let sql = "SELECT kind, methods, client_version, ip, started, finished, bytes_in, bytes_out,
+outcome, error FROM sync_log WHERE username=? ORDER BY started DESC LIMIT ?";
+    let conn = Connection::open(server_db)?;
+    let mut stmt = conn.prepare(sql)?;
+    let entries = stmt
+        .query_map(rusqlite::params![username, limit as i64], |row| {
+            Ok(SyncLogEntry {
+                kind: row.get(0)?,
+                methods: row.get(1)?,
+                client_version: row.get(2)?,
+                ip: row.get(3)?,
+                started: row.get(4)?,
+                finished: row.get(5)?,
+                bytes_in: row.get(6)?,
+                bytes_out: row.get(7)?,
+                outcome: row.get(8)?,
+                error: row.get(9)?,
+            })
+        })?
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(entries)
+}
+
+/// when the last successful sync session of each user finished,unix time in
+/// milliseconds.
+pub fn last_syncs<P: AsRef<Path>>(server_db: P) -> Result<HashMap<String, i64>, rusqlite::Error> {
+    let sql = "SELECT username, max(finished) FROM sync_log WHERE outcome='ok' GROUP BY username";
+    let conn = Connection::open(server_db)?;
+    let mut stmt = conn.prepare(sql)?;
+    let last = stmt
+        .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+        .collect::<Result<HashMap<_, _>, _>>()?;
+    Ok(last)
+}
+
+/// print the sync history of user for the `log` subcommand
+pub fn print_sync_log<P: AsRef<Path>>(
+    server_db: P,
+    username: &str,
+    limit: usize,
+) -> Result<(), rusqlite::Error> {
+    let entries = sync_log(server_db, username, limit)?;
+    if entries.is_empty() {
+        println!("no sync logged for user {username}");
+        return Ok(());
+    }
+    println!(
+        "{:<21}{:<15}{:>9}{:>12}{:>12}  {:<9}{:<40}{:<21}methods",
+        "started", "kind", "secs", "bytes in", "bytes out", "outcome", "client", "ip"
+    );
+    for e in entries {
+        let started = Local
+            .timestamp_millis_opt(e.started)
+            .single()
+            .map(|t| t.format("%Y-%m-%d %H:%M:%S").to_string())
+            .unwrap_or_default();
+        let secs = (e.finished - e.started) as f64 / 1000.0;
+        println!(
+            "{:<21}{:<15}{:>9.1}{:>12}{:>12}  {:<9}{:<40}{:<21}{}",
+            started,
+            e.kind,
+            secs,
+            e.bytes_in,
+            e.bytes_out,
+            e.outcome,
+            e.client_version,
+            e.ip,
+            e.methods
+        );
+        if let Some(error) = e.error {
+            println!("    error: {error}");
+        }
+    }
+    Ok(())
+}
@@ -1,10 +1,19 @@
-#[cfg(feature = "account")]
 use crate::config::Account;
 
+use crate::config::ConfigHashing;
+use crate::db::add_missing_columns;
+use crate::hostkey::{
+    list_devices, revoke_device, revoke_host_keys, upgrade_hostkeys_table, CREATE_HOSTKEYS_TABLE,
+};
+use crate::lockout::{unlock, CREATE_LOCKOUTS_TABLE, CREATE_LOGIN_ATTEMPTS_TABLE};
 use crate::parse_args::UserCommand;
 
-use rand::{rngs::OsRng, RngCore};
-use rusqlite::Connection;
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::{Algorithm, Argon2, Params, Version};
+use chrono::{Local, TimeZone};
+use rand::rngs::OsRng;
+use rusqlite::{Connection, OptionalExtension};
+use serde::Serialize;
 use sha2::{Digest, Sha256};
 use std::fs;
 use std::io;
@@ -23,6 +32,32 @@ pub enum UserError {
     Authentication(String),
     #[error("Path not found error")]
     PathNotFound,
+    #[error("Password hashing error: {0}")]
+    PasswordHash(String),
+}
+
+/// columns added to table `auth` after its creation
+const AUTH_COLUMNS: [(&str, &str); 4] = [
+    ("disabled", "INTEGER NOT NULL DEFAULT 0"),
+    // storage quotas,NULL means unlimited
+    ("quota_collection_bytes", "INTEGER"),
+    ("quota_media_bytes", "INTEGER"),
+    ("quota_media_files", "INTEGER"),
+];
+
+/// a user as stored in auth db,without its password hash
+#[derive(Debug, Clone, Serialize)]
+pub struct UserInfo {
+    pub username: String,
+    pub disabled: bool,
+}
+
+/// storage limits of a user,`None` means unlimited
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct Quota {
+    pub collection_bytes: Option<u64>,
+    pub media_bytes: Option<u64>,
+    pub media_files: Option<u64>,
 }
 
 impl From<(rusqlite::Connection, rusqlite::Error)> for UserError {
@@ -32,29 +67,39 @@ impl From<(rusqlite::Connection, rusqlite::Error)> for UserError {
     }
 }
 
-fn create_salt() -> String {
-    // create salt
-    let mut key = [0u8; 8];
-    OsRng.fill_bytes(&mut key);
-    hex::encode(key)
-}
-fn set_password_for_user<P: AsRef<Path>>(
+pub fn set_password_for_user<P: AsRef<Path>>(
     username: &str,
     new_password: &str,
     dbpath: P,
+    hashing: &ConfigHashing,
 ) -> Result<(), UserError> {
     if user_exists(username, &dbpath)? {
-        let salt = create_salt();
-        let hash = create_pass_hash(username, new_password, &salt);
+        let hash = create_pass_hash(new_password, hashing)?;
         let sql = "UPDATE auth SET hash=? WHERE username=?";
-        let conn = Connection::open(dbpath)?;
+        let conn = Connection::open(&dbpath)?;
         conn.execute(sql, [hash.as_str(), username])?;
         conn.close()?;
+        // devices have to log in again with the new password
+        revoke_host_keys(username, &dbpath)?;
     }
 
     Ok(())
 }
 
+/// usernames are used as folder names,so reject anything that could escape the
+/// collections folder.
+fn validate_username(username: &str) -> Result<(), UserError> {
+    if username.is_empty()
+        || username == "."
+        || username == ".."
+        || username.contains(['/', '\\', '\0'])
+    {
+        return Err(UserError::MissingValues(format!(
+            "invalid username {username:?}"
+        )));
+    }
+    Ok(())
+}
 fn create_user_dir(path: PathBuf) -> Result<(), UserError> {
     if !path.exists() {
         fs::create_dir_all(path)?;
@@ -65,10 +110,11 @@ fn add_user_to_auth_db<P: AsRef<Path>>(
     username: &str,
     password: &str,
     dbpath: P,
+    hashing: &ConfigHashing,
 ) -> Result<(), UserError> {
-    let salt = create_salt();
-    let pass_hash = create_pass_hash(username, password, &salt);
-    let sql = "INSERT INTO auth VALUES (?, ?)";
+    validate_username(username)?;
+    let pass_hash = create_pass_hash(password, hashing)?;
+    let sql = "INSERT INTO auth (username, hash) VALUES (?, ?)";
     let conn = Connection::open(&dbpath)?;
     conn.execute(sql, [username, pass_hash.as_str()])?;
     conn.close()?;
@@ -79,23 +125,32 @@ fn add_user_to_auth_db<P: AsRef<Path>>(
     create_user_dir(user_dir)?;
     Ok(())
 }
-pub fn add_user<P: AsRef<Path>>(args: &[String], dbpath: P) -> Result<(), UserError> {
+pub fn add_user<P: AsRef<Path>>(
+    args: &[String],
+    dbpath: P,
+    hashing: &ConfigHashing,
+) -> Result<(), UserError> {
     let username = &args[0];
     let password = &args[1];
-    add_user_to_auth_db(username, password, dbpath)?;
+    add_user_to_auth_db(username, password, dbpath, hashing)?;
     Ok(())
 }
-fn passwd<P: AsRef<Path>>(args: &[String], dbpath: P) -> Result<(), UserError> {
+fn passwd<P: AsRef<Path>>(
+    args: &[String],
+    dbpath: P,
+    hashing: &ConfigHashing,
+) -> Result<(), UserError> {
     let username = &args[0];
     let password = &args[1];
-    set_password_for_user(username, password, dbpath)?;
+    set_password_for_user(username, password, dbpath, hashing)?;
     Ok(())
 }
-fn del_user<P: AsRef<Path>>(username: &str, dbpath: P) -> Result<(), UserError> {
+pub fn del_user<P: AsRef<Path>>(username: &str, dbpath: P) -> Result<(), UserError> {
     let sql = "DELETE FROM auth WHERE username=?";
-    let conn = Connection::open(dbpath)?;
+    let conn = Connection::open(&dbpath)?;
     conn.execute(sql, [username])?;
     conn.close()?;
+    revoke_host_keys(username, &dbpath)?;
     Ok(())
 }
 pub fn create_auth_db<P: AsRef<Path>>(p: P) -> Result<(), UserError> {
@@ -103,21 +158,34 @@ pub fn create_auth_db<P: AsRef<Path>>(p: P) -> Result<(), UserError> {
 (username VARCHAR PRIMARY KEY, hash VARCHAR)";
     let conn = Connection::open(p)?;
     conn.execute(sql, [])?;
+    add_missing_columns(&conn, "auth", &AUTH_COLUMNS)?;
+    conn.execute(CREATE_HOSTKEYS_TABLE, [])?;
+    upgrade_hostkeys_table(&conn)?;
+    conn.execute(CREATE_LOGIN_ATTEMPTS_TABLE, [])?;
+    conn.execute(CREATE_LOCKOUTS_TABLE, [])?;
     conn.close()?;
 
     Ok(())
 }
 /// command-line user management
-pub fn user_manage<P: AsRef<Path>>(cmd: &UserCommand, dbpath: P) -> Result<(), UserError> {
+pub fn user_manage<P: AsRef<Path>>(
+    cmd: &UserCommand,
+    dbpath: P,
+    hashing: &ConfigHashing,
+) -> Result<(), UserError> {
     match cmd {
         UserCommand::User {
             add,
             del,
             pass,
             list,
+            devices,
+            revoke,
+            quota,
+            unlock: unlock_targets,
         } => {
             if let Some(account) = add {
-                add_user(account, &dbpath)?;
+                add_user(account, &dbpath, hashing)?;
             }
             if let Some(users) = del {
                 for u in users {
@@ -125,7 +193,7 @@ pub fn user_manage<P: AsRef<Path>>(cmd: &UserCommand, dbpath: P) -> Result<(), U
                 }
             }
             if let Some(account) = pass {
-                passwd(account, &dbpath)?;
+                passwd(account, &dbpath, hashing)?;
             }
             if *list {
                 let user_list = user_list(&dbpath)?;
@@ -133,11 +201,74 @@ pub fn user_manage<P: AsRef<Path>>(cmd: &UserCommand, dbpath: P) -> Result<(), U
                     v.into_iter().for_each(|i| println!("{i}"));
                 }
             }
+            if let Some(username) = devices {
+                print_devices(username, &dbpath)?;
+            }
+            if let Some(args) = revoke {
+                revoke_device(&args[0], &args[1], &dbpath)?;
+                println!("revoked device {} of user {}", args[1], args[0]);
+            }
+            if let Some(args) = quota {
+                if !user_exists(&args[0], &dbpath)? {
+                    return Err(UserError::MissingValues(format!(
+                        "no such user {}",
+                        args[0]
+                    )));
+                }
+                set_user_quota(&args[0], quota_from_args(args)?, &dbpath)?;
+                println!("quota of user {} updated", args[0]);
+            }
+            if let Some(targets) = unlock_targets {
+                for t in targets {
+                    if unlock(t, &dbpath)? {
+                        println!("{t} unlocked");
+                    } else {
+                        println!("{t} was not locked out,its failed logins are cleared");
+                    }
+                }
+            }
+        }
+        UserCommand::Backup { .. }
+        | UserCommand::Check { .. }
+        | UserCommand::Log { .. }
+        | UserCommand::Media { .. } => {
+            return Err(UserError::MissingValues(
+                "not a user management command".to_string(),
+            ))
         }
     }
 
     Ok(())
 }
+fn print_devices<P: AsRef<Path>>(username: &str, dbpath: P) -> Result<(), UserError> {
+    let devices = list_devices(username, dbpath)?;
+    if devices.is_empty() {
+        println!("no device found for user {username}");
+        return Ok(());
+    }
+    println!(
+        "{:<10}{:<24}{:<40}{:<21}status",
+        "device", "last seen", "client", "ip"
+    );
+    for d in devices {
+        let last_seen = Local
+            .timestamp_opt(d.last_seen, 0)
+            .single()
+            .map(|t| t.format("%Y-%m-%d %H:%M:%S").to_string())
+            .unwrap_or_default();
+        let client = if d.client_version.is_empty() {
+            "unknown"
+        } else {
+            d.client_version.as_str()
+        };
+        let status = if d.revoked { "revoked" } else { "active" };
+        println!(
+            "{:<10}{:<24}{:<40}{:<21}{status}",
+            d.id, last_seen, client, d.ip
+        );
+    }
+    Ok(())
+}
 pub fn user_list<P: AsRef<Path>>(dbpath: P) -> Result<Option<Vec<String>>, UserError> {
     let sql = "SELECT username FROM auth";
     let conn = Connection::open(dbpath)?;
@@ -151,6 +282,100 @@ pub fn user_list<P: AsRef<Path>>(dbpath: P) -> Result<Option<Vec<String>>, UserE
         Ok(Some(v1))
     }
 }
+/// list users together with their status.
+pub fn user_infos<P: AsRef<Path>>(dbpath: P) -> Result<Vec<UserInfo>, UserError> {
+    let sql = "SELECT username,disabled FROM auth ORDER BY username";
+    let conn = Connection::open(dbpath)?;
+    let mut stmt = conn.prepare(sql)?;
+    let users = stmt
+        .query_map([], |r| {
+            Ok(UserInfo {
+                username: r.get(0)?,
+                disabled: r.get(1)?,
+            })
+        })?
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(users)
+}
+/// disable or enable a user,a disabled user can neither log in nor sync and
+/// the host keys it holds are revoked.
+pub fn set_user_disabled<P: AsRef<Path>>(
+    username: &str,
+    disabled: bool,
+    dbpath: P,
+) -> Result<(), UserError> {
+    let sql = "UPDATE auth SET disabled=? WHERE username=?";
+    let conn = Connection::open(&dbpath)?;
+    conn.execute(sql, rusqlite::params![disabled, username])?;
+    conn.close()?;
+    if disabled {
+        revoke_host_keys(username, &dbpath)?;
+    }
+    Ok(())
+}
+/// quota of a user,unlimited if the user does not exist.
+pub fn user_quota<P: AsRef<Path>>(username: &str, dbpath: P) -> Result<Quota, UserError> {
+    let sql = "SELECT quota_collection_bytes,quota_media_bytes,quota_media_files FROM auth
+WHERE username=?";
+    let conn = Connection::open(dbpath)?;
+    let quota = conn
+        .query_row(sql, [username], |r| {
+            Ok(Quota {
+                collection_bytes: r.get::<_, Option<i64>>(0)?.map(|v| v as u64),
+                media_bytes: r.get::<_, Option<i64>>(1)?.map(|v| v as u64),
+                media_files: r.get::<_, Option<i64>>(2)?.map(|v| v as u64),
+            })
+        })
+        .optional()?;
+    Ok(quota.unwrap_or_default())
+}
+pub fn set_user_quota<P: AsRef<Path>>(
+    username: &str,
+    quota: Quota,
+    dbpath: P,
+) -> Result<(), UserError> {
+    let sql = "UPDATE auth SET quota_collection_bytes=?,quota_media_bytes=?,quota_media_files=?
+WHERE username=?";
+    let conn = Connection::open(dbpath)?;
+    conn.execute(
+        sql,
+        rusqlite::params![
+            quota.collection_bytes.map(|v| v as i64),
+            quota.media_bytes.map(|v| v as i64),
+            quota.media_files.map(|v| v as i64),
+            username
+        ],
+    )?;
+    conn.close()?;
+    Ok(())
+}
+/// parse the arguments of `--quota`,sizes are given in megabytes and 0 means
+/// unlimited.
+fn quota_from_args(args: &[String]) -> Result<Quota, UserError> {
+    // quotas are stored as sqlite integers
+    let parse = |s: &String| {
+        s.parse::<u64>()
+            .ok()
+            .filter(|v| *v <= i64::MAX as u64)
+            .ok_or_else(|| UserError::MissingValues(format!("invalid quota value {s}")))
+            .map(|v| Some(v).filter(|v| *v > 0))
+    };
+    let megs = |v: Option<u64>| {
+        v.map(|v| {
+            v.checked_mul(1024 * 1024)
+                .filter(|b| *b <= i64::MAX as u64)
+                .ok_or_else(|| {
+                    UserError::MissingValues(format!("quota of {v} megabytes is too large"))
+                })
+        })
+        .transpose()
+    };
+    Ok(Quota {
+        collection_bytes: megs(parse(&args[1])?)?,
+        media_bytes: megs(parse(&args[2])?)?,
+        media_files: parse(&args[3])?,
+    })
+}
 pub fn user_exists<P: AsRef<Path>>(username: &str, dbpath: P) -> Result<bool, UserError> {
     let uservec = user_list(dbpath)?;
     match uservec {
@@ -158,7 +383,28 @@ pub fn user_exists<P: AsRef<Path>>(username: &str, dbpath: P) -> Result<bool, Us
         _ => Ok(false),
     }
 }
-fn create_pass_hash(username: &str, password: &str, salt: &str) -> String {
+fn argon2_from_conf(hashing: &ConfigHashing) -> Result<Argon2<'static>, UserError> {
+    let params = Params::new(
+        hashing.memory_cost,
+        hashing.time_cost,
+        hashing.parallelism,
+        None,
+    )
+    .map_err(|e| UserError::PasswordHash(e.to_string()))?;
+    Ok(Argon2::new(Algorithm::Argon2id, Version::V0x13, params))
+}
+/// hash a password into a PHC-format Argon2id string,the salt and cost
+/// parameters are embedded in the string itself.
+pub fn create_pass_hash(password: &str, hashing: &ConfigHashing) -> Result<String, UserError> {
+    let salt = SaltString::generate(&mut OsRng);
+    let hash = argon2_from_conf(hashing)?
+        .hash_password(password.as_bytes(), &salt)
+        .map_err(|e| UserError::PasswordHash(e.to_string()))?;
+    Ok(hash.to_string())
+}
+/// hashes written by older versions: sha256(username+password+salt) followed by
+/// the 16 hex characters of the salt.
+fn create_legacy_pass_hash(username: &str, password: &str, salt: &str) -> String {
     // create a Sha256 object
     let mut hasher = Sha256::new();
     // write input message
@@ -170,32 +416,69 @@ fn create_pass_hash(username: &str, password: &str, salt: &str) -> String {
     let pass_hash = format!("{result:x}{salt}");
     pass_hash
 }
-/// extract salt from a hash which is the last 16 characters
-pub fn compute_hash(username: &str, password: &str, hash: &str) -> String {
-    let salt = &hash[(hash.chars().count() - 16)..];
-
-    create_pass_hash(username, password, salt)
+/// compare two byte strings in time independent of where they differ
+pub(crate) fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+/// legacy hashes are plain hex,while PHC strings always start with `$`.
+pub fn is_legacy_hash(hash: &str) -> bool {
+    !hash.starts_with('$')
+}
+/// check a password against the hash stored in auth db,accepting both Argon2
+/// PHC strings and legacy salted sha256 hashes.
+pub fn verify_password(username: &str, password: &str, hash: &str) -> bool {
+    if is_legacy_hash(hash) {
+        // extract salt from a hash which is the last 16 characters
+        return match hash.get(hash.len().saturating_sub(16)..) {
+            Some(salt) => constant_time_eq(
+                create_legacy_pass_hash(username, password, salt).as_bytes(),
+                hash.as_bytes(),
+            ),
+            None => false,
+        };
+    }
+    match PasswordHash::new(hash) {
+        // cost parameters are read from the PHC string
+        Ok(parsed) => Argon2::default()
+            .verify_password(password.as_bytes(), &parsed)
+            .is_ok(),
+        Err(_) => false,
+    }
+}
+/// store hash as the hash of user,return it.
+///
+/// replaces a legacy hash with an Argon2id one made by `create_pass_hash` after
+/// a successful login,as it is the only time the plain password is known.
+pub fn store_hash<P: AsRef<Path>>(
+    username: &str,
+    hash: String,
+    dbpath: P,
+) -> Result<String, UserError> {
+    let sql = "UPDATE auth SET hash=? WHERE username=?";
+    let conn = Connection::open(dbpath)?;
+    conn.execute(sql, [hash.as_str(), username])?;
+    conn.close()?;
+    Ok(hash)
 }
-/// here the account argument is read from cnfig file.
+/// here the account argument is read from cnfig file or env vars.
 ///
 /// do not panic if encountered error
-#[cfg(feature = "account")]
-pub fn create_user_from_conf<P: AsRef<Path>>(account: Account, dbpath: P) {
+pub fn create_user_from_conf<P: AsRef<Path>>(account: Account, dbpath: P, hashing: &ConfigHashing) {
     let username = account.username();
     let pass = account.password();
     if username.is_some() && pass.is_some() {
         let user_list = match user_list(&dbpath) {
             Ok(l) => l,
-            Err(_) => return (),
+            Err(_) => return,
         };
         // do nothing and return if user already exists in db.
         if let Some(list) = user_list {
-            if list.contains(&username.as_ref().unwrap()) {
-                return ();
+            if list.contains(username.as_ref().unwrap()) {
+                return;
             }
         }
         let args = [username.clone().unwrap(), pass.clone().unwrap()];
-        if add_user(args.as_slice(), dbpath).is_err() {
+        if add_user(args.as_slice(), dbpath, hashing).is_err() {
             println!("添加用户失败");
         } else {
             println!("添加用户 {} 成功", username.as_ref().unwrap());
@@ -0,0 +1,208 @@
+//! malformed requests fed through the request-decoding middleware must be
+//! answered with an error status,never panic a worker.
+use actix_web::http::header::{HeaderValue, CONTENT_TYPE};
+use actix_web::http::StatusCode;
+use actix_web::{test, web, App, HttpResponse};
+use anki::sync::request::SyncRequest;
+use ankisyncd::request::SyncRequestWrapper;
+use rand::{Rng, RngCore};
+
+const SYNC_HEADER: &str = "anki-sync";
+const BOUNDARY: &str = "Anki-sync-boundary";
+
+async fn echo(req: web::ReqData<SyncRequest<Vec<u8>>>) -> HttpResponse {
+    HttpResponse::Ok().body(req.into_inner().data)
+}
+
+macro_rules! app {
+    () => {
+        test::init_service(
+            App::new().service(
+                web::resource("/sync/{method}")
+                    .wrap(SyncRequestWrapper)
+                    .to(echo),
+            ),
+        )
+        .await
+    };
+}
+
+/// status of the response,errors of the middleware are turned into responses
+/// by the http server
+macro_rules! status {
+    ($app:expr, $req:expr) => {
+        match test::try_call_service(&$app, $req).await {
+            Ok(res) => res.status(),
+            Err(e) => e.as_response_error().status_code(),
+        }
+    };
+}
+
+fn sync_header(version: u8) -> String {
+    format!(r#"{{"v":{version},"k":"key","c":"test,1.0,linux","s":"session"}}"#)
+}
+
+fn multipart(fields: &[(&str, &[u8])]) -> Vec<u8> {
+    let mut body = vec![];
+    for (name, value) in fields {
+        body.extend_from_slice(
+            format!("--{BOUNDARY}\r\nContent-Disposition: form-data; name=\"{name}\"\r\n\r\n")
+                .as_bytes(),
+        );
+        body.extend_from_slice(value);
+        body.extend_from_slice(b"\r\n");
+    }
+    body.extend_from_slice(format!("--{BOUNDARY}--\r\n").as_bytes());
+    body
+}
+
+fn multipart_request(body: Vec<u8>) -> test::TestRequest {
+    test::TestRequest::post()
+        .uri("/sync/meta")
+        .insert_header((
+            CONTENT_TYPE,
+            format!("multipart/form-data; boundary={BOUNDARY}"),
+        ))
+        .set_payload(body)
+}
+
+#[actix_web::test]
+async fn valid_zstd_request_is_decoded() {
+    let app = app!();
+    let body = zstd::encode_all(&b"{}"[..], 0).unwrap();
+    let req = test::TestRequest::post()
+        .uri("/sync/meta")
+        .insert_header((SYNC_HEADER, sync_header(11)))
+        .set_payload(body)
+        .to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(test::read_body(res).await, &b"{}"[..]);
+}
+
+#[actix_web::test]
+async fn garbage_zstd_body_is_bad_request() {
+    let app = app!();
+    let req = test::TestRequest::post()
+        .uri("/sync/meta")
+        .insert_header((SYNC_HEADER, sync_header(11)))
+        .set_payload(&b"definitely not zstd"[..])
+        .to_request();
+    assert_eq!(status!(app, req), StatusCode::BAD_REQUEST);
+}
+
+#[actix_web::test]
+async fn malformed_sync_header_is_bad_request() {
+    let app = app!();
+    for header in ["", "{", "[]", r#"{"v":"eleven"}"#, "null"] {
+        let req = test::TestRequest::post()
+            .uri("/sync/meta")
+            .insert_header((SYNC_HEADER, header))
+            .to_request();
+        assert_eq!(
+            status!(app, req),
+            StatusCode::BAD_REQUEST,
+            "header {header:?}"
+        );
+    }
+    let req = test::TestRequest::post()
+        .uri("/sync/meta")
+        .insert_header((SYNC_HEADER, HeaderValue::from_bytes(b"{\xff}").unwrap()))
+        .to_request();
+    assert_eq!(status!(app, req), StatusCode::BAD_REQUEST);
+}
+
+#[actix_web::test]
+async fn unsupported_sync_version_is_not_implemented() {
+    let app = app!();
+    for version in [0, 1, 99, 255] {
+        let req = test::TestRequest::post()
+            .uri("/sync/meta")
+            .insert_header((SYNC_HEADER, sync_header(version)))
+            .to_request();
+        assert_eq!(
+            status!(app, req),
+            StatusCode::NOT_IMPLEMENTED,
+            "version {version}"
+        );
+    }
+}
+
+#[actix_web::test]
+async fn multipart_request_is_decoded() {
+    let app = app!();
+    let body = multipart(&[("c", b"0"), ("k", b"key"), ("data", b"{\"v\":1}")]);
+    let res = test::call_service(&app, multipart_request(body).to_request()).await;
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(test::read_body(res).await, &b"{\"v\":1}"[..]);
+}
+
+#[actix_web::test]
+async fn garbage_gzip_in_multipart_is_bad_request() {
+    let app = app!();
+    let body = multipart(&[("c", b"1"), ("k", b"key"), ("data", b"not gzip")]);
+    let req = multipart_request(body).to_request();
+    assert_eq!(status!(app, req), StatusCode::BAD_REQUEST);
+}
+
+#[actix_web::test]
+async fn non_utf8_multipart_field_is_bad_request() {
+    let app = app!();
+    let body = multipart(&[("c", b"0"), ("k", b"\xff\xfe")]);
+    let req = multipart_request(body).to_request();
+    assert_eq!(status!(app, req), StatusCode::BAD_REQUEST);
+}
+
+/// bodies cut before their closing boundary are refused
+#[actix_web::test]
+async fn truncated_multipart_is_bad_request() {
+    let app = app!();
+    let mut body = multipart(&[("c", b"0"), ("k", b"key"), ("data", b"{}")]);
+    let closing = format!("--{BOUNDARY}--\r\n").len();
+    for len in (0..body.len() - closing).rev().step_by(7) {
+        body.truncate(len);
+        let status = status!(app, multipart_request(body.clone()).to_request());
+        assert_eq!(status, StatusCode::BAD_REQUEST, "truncated to {len}");
+    }
+}
+
+/// random bodies and headers,every answer must be a decoding error or success
+#[actix_web::test]
+async fn random_payloads_do_not_panic() {
+    let app = app!();
+    let mut rng = rand::thread_rng();
+    for _ in 0..500 {
+        let mut body = vec![0u8; rng.gen_range(0..4096)];
+        rng.fill_bytes(&mut body);
+        let req = match rng.gen_range(0..4) {
+            0 => test::TestRequest::post()
+                .uri("/sync/meta")
+                .insert_header((SYNC_HEADER, sync_header(rng.gen()))),
+            1 => {
+                let mut header = vec![0u8; rng.gen_range(1..64)];
+                rng.fill(&mut header[..]);
+                // header values cannot hold control characters
+                header.retain(|b| *b >= 0x20 && *b != 0x7f);
+                match HeaderValue::from_bytes(&header) {
+                    Ok(v) => test::TestRequest::post()
+                        .uri("/sync/meta")
+                        .insert_header((SYNC_HEADER, v)),
+                    Err(_) => test::TestRequest::post().uri("/sync/meta"),
+                }
+            }
+            2 => multipart_request(vec![]),
+            _ => test::TestRequest::post().uri("/sync/meta"),
+        };
+        let status = status!(app, req.set_payload(body).to_request());
+        assert!(
+            matches!(
+                status,
+                StatusCode::OK
+                    | StatusCode::BAD_REQUEST
+                    | StatusCode::PAYLOAD_TOO_LARGE
+                    | StatusCode::NOT_IMPLEMENTED
+            ),
+            "unexpected status {status}"
+        );
+    }
+}